@@ -0,0 +1,262 @@
+// Jackson Coxson
+// Endpoints for whoever runs the server, gated behind ADMIN_TOKEN
+
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Serialize;
+
+use crate::{
+    backend::BackendStats,
+    common,
+    ddi::DdiInfo,
+    launch::{self, TunnelMode},
+    mount::{self, MountInfo},
+    netmuxd::{self, RecentEvent, UsbmuxdDevice},
+    runner::WorkerInfo,
+    tss::TicketStats,
+    tunneld::{Tunnel, TunneldClient},
+    tunnels::{self, TunnelInfo, TunnelState},
+    JitStreamerState,
+};
+
+const TOKEN_VAR: &str = "ADMIN_TOKEN";
+
+#[derive(Serialize)]
+pub struct WorkerEntry {
+    worker: u32,
+    #[serde(flatten)]
+    info: WorkerInfo,
+}
+
+#[derive(Serialize)]
+pub struct WorkersReturn {
+    ok: bool,
+    workers: Vec<WorkerEntry>,
+}
+
+pub async fn workers(
+    headers: HeaderMap,
+    State(state): State<JitStreamerState>,
+) -> Result<Json<WorkersReturn>, (StatusCode, &'static str)> {
+    common::check_bearer(&headers, TOKEN_VAR)?;
+    let workers = state
+        .workers
+        .lock()
+        .await
+        .iter()
+        .map(|(worker, info)| WorkerEntry {
+            worker: *worker,
+            info: info.clone(),
+        })
+        .collect();
+    Ok(Json(WorkersReturn { ok: true, workers }))
+}
+
+#[derive(Serialize)]
+pub struct BackendsReturn {
+    ok: bool,
+    primary: &'static str,
+    canary: Option<&'static str>,
+    canary_percent: u8,
+    stats: BTreeMap<&'static str, BackendStats>,
+}
+
+/// Which launch backends are in use and how many launches each has done
+pub async fn backends(
+    headers: HeaderMap,
+    State(state): State<JitStreamerState>,
+) -> Result<Json<BackendsReturn>, (StatusCode, &'static str)> {
+    common::check_bearer(&headers, TOKEN_VAR)?;
+    let canary = state.backends.canary();
+    Ok(Json(BackendsReturn {
+        ok: true,
+        primary: state.backends.primary(),
+        canary: canary.map(|c| c.0),
+        canary_percent: canary.map(|c| c.1).unwrap_or(0),
+        stats: state.backends.stats(),
+    }))
+}
+
+#[derive(Serialize)]
+pub struct NetmuxdReturn {
+    ok: bool,
+    devices: Vec<UsbmuxdDevice>,
+    recent_events: Vec<RecentEvent>,
+    error: Option<String>,
+}
+
+/// The devices netmuxd is tracking and what it's reported lately
+pub async fn netmuxd_devices(
+    headers: HeaderMap,
+) -> Result<Json<NetmuxdReturn>, (StatusCode, &'static str)> {
+    common::check_bearer(&headers, TOKEN_VAR)?;
+    Ok(Json(match netmuxd::list_devices().await {
+        Ok(devices) => NetmuxdReturn {
+            ok: true,
+            devices,
+            recent_events: netmuxd::recent_events(),
+            error: None,
+        },
+        Err(e) => NetmuxdReturn {
+            ok: false,
+            devices: Vec::new(),
+            recent_events: netmuxd::recent_events(),
+            error: Some(e.to_string()),
+        },
+    }))
+}
+
+#[derive(Serialize)]
+pub struct TunnelEntry {
+    udid: String,
+    #[serde(flatten)]
+    tunnel: Tunnel,
+    healthy: bool,
+}
+
+#[derive(Serialize)]
+pub struct TunnelsReturn {
+    ok: bool,
+    tunnels: Vec<TunnelEntry>,
+    /// The built in tunnels, including ones coming up or failed
+    builtin: Option<Vec<TunnelInfo>>,
+    error: Option<String>,
+}
+
+/// Every tunnel, and whether each device answers through it
+pub async fn tunnels(
+    headers: HeaderMap,
+) -> Result<Json<TunnelsReturn>, (StatusCode, &'static str)> {
+    common::check_bearer(&headers, TOKEN_VAR)?;
+    if launch::tunnel_mode() == TunnelMode::Builtin {
+        let builtin = tunnels::list();
+        let mut entries = Vec::new();
+        for info in &builtin {
+            if let TunnelState::Up { tunnel, .. } = &info.state {
+                entries.push(TunnelEntry {
+                    udid: info.udid.clone(),
+                    tunnel: tunnel.clone(),
+                    healthy: tunnel.is_healthy().await,
+                });
+            }
+        }
+        return Ok(Json(TunnelsReturn {
+            ok: true,
+            tunnels: entries,
+            builtin: Some(builtin),
+            error: None,
+        }));
+    }
+
+    let tunnels = match TunneldClient::from_env().list().await {
+        Ok(t) => t,
+        Err(e) => {
+            return Ok(Json(TunnelsReturn {
+                ok: false,
+                tunnels: Vec::new(),
+                builtin: None,
+                error: Some(e.to_string()),
+            }))
+        }
+    };
+
+    let mut entries = Vec::new();
+    for (udid, tunnels) in tunnels {
+        for tunnel in tunnels {
+            let healthy = tunnel.is_healthy().await;
+            entries.push(TunnelEntry {
+                udid: udid.clone(),
+                tunnel,
+                healthy,
+            });
+        }
+    }
+    entries.sort_by(|a, b| a.udid.cmp(&b.udid));
+    Ok(Json(TunnelsReturn {
+        ok: true,
+        tunnels: entries,
+        builtin: None,
+        error: None,
+    }))
+}
+
+#[derive(Serialize)]
+pub struct DdiReturn {
+    ok: bool,
+    directory: String,
+    loaded_at: Option<String>,
+    images: Vec<DdiInfo>,
+    tss: TicketStats,
+    error: Option<String>,
+}
+
+impl DdiReturn {
+    fn new(state: &JitStreamerState, error: Option<String>) -> Self {
+        Self {
+            ok: error.is_none(),
+            directory: state.ddi.directory().display().to_string(),
+            loaded_at: state.ddi.loaded_at(),
+            images: state.ddi.list(),
+            tss: state.tickets.stats(),
+            error,
+        }
+    }
+}
+
+/// The developer disk images the server holds, newest first
+pub async fn ddi(
+    headers: HeaderMap,
+    State(state): State<JitStreamerState>,
+) -> Result<Json<DdiReturn>, (StatusCode, &'static str)> {
+    common::check_bearer(&headers, TOKEN_VAR)?;
+    Ok(Json(DdiReturn::new(&state, None)))
+}
+
+/// Loads the developer disk images from disk again, like SIGHUP
+pub async fn reload_ddi(
+    headers: HeaderMap,
+    State(state): State<JitStreamerState>,
+) -> Result<Json<DdiReturn>, (StatusCode, &'static str)> {
+    common::check_bearer(&headers, TOKEN_VAR)?;
+    let error = state.ddi.reload().await.err();
+    Ok(Json(DdiReturn::new(&state, error)))
+}
+
+#[derive(Serialize)]
+pub struct MountsReturn {
+    ok: bool,
+    mounts: Vec<MountInfo>,
+}
+
+/// The mounts the server is keeping track of, running or finished
+pub async fn mounts(
+    headers: HeaderMap,
+    State(state): State<JitStreamerState>,
+) -> Result<Json<MountsReturn>, (StatusCode, &'static str)> {
+    common::check_bearer(&headers, TOKEN_VAR)?;
+    Ok(Json(MountsReturn {
+        ok: true,
+        mounts: mount::list(&state).await,
+    }))
+}
+
+/// Stops the device's mount so its next /mount starts again
+pub async fn forget_mount(
+    headers: HeaderMap,
+    State(state): State<JitStreamerState>,
+    Path(udid): Path<String>,
+) -> Result<Json<MountsReturn>, (StatusCode, &'static str)> {
+    common::check_bearer(&headers, TOKEN_VAR)?;
+    if !mount::forget(&state, &udid).await {
+        return Err((StatusCode::NOT_FOUND, "no mount for that device"));
+    }
+    Ok(Json(MountsReturn {
+        ok: true,
+        mounts: mount::list(&state).await,
+    }))
+}
@@ -0,0 +1,571 @@
+// Jackson Coxson
+// The ways a launch job can be carried out. LAUNCH_BACKEND picks one, and
+// LAUNCH_CANARY_BACKEND can take LAUNCH_CANARY_PERCENT of the jobs to compare against it.
+
+use std::{
+    collections::BTreeMap, future::Future, net::IpAddr, pin::Pin, process::Stdio, sync::Mutex,
+    time::Duration,
+};
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::{
+    debug_server::{self, JobKind, JobStatus, LaunchJob, LaunchOptions},
+    launch::{self, LaunchError, TunnelMode},
+    runner, session,
+    worker::SECRET_VAR,
+    JitStreamerState,
+};
+
+/// A remote launch waits in the remote server's queue before it runs
+const REMOTE_TIMEOUT: Duration = Duration::from_secs(180);
+
+pub type LaunchFuture<'a> = Pin<Box<dyn Future<Output = Result<u64, LaunchError>> + Send + 'a>>;
+
+/// Given a claimed job, enables JIT and returns the PID it was enabled for
+pub trait LaunchBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Whether this backend can do the job, the native backend does the rest
+    fn supports(&self, job: &LaunchJob) -> bool;
+
+    /// Runs the job for the worker with the given index
+    fn run<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        worker: u32,
+        job: &'a LaunchJob,
+    ) -> LaunchFuture<'a>;
+}
+
+/// Runs the launch in-process, see launch.rs
+pub struct Native;
+
+impl LaunchBackend for Native {
+    fn name(&self) -> &'static str {
+        "native"
+    }
+
+    fn supports(&self, _job: &LaunchJob) -> bool {
+        true
+    }
+
+    fn run<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        _worker: u32,
+        job: &'a LaunchJob,
+    ) -> LaunchFuture<'a> {
+        Box::pin(async move {
+            match &job.kind {
+                JobKind::Launch { bundle_id, options } if options.persistent => {
+                    let ip = job
+                        .ip
+                        .parse()
+                        .map_err(|_| LaunchError::BadAddress(job.ip.clone()))?;
+                    let (pid, debugserver) = launch::launch_session(
+                        &job.udid,
+                        &job.ip,
+                        bundle_id,
+                        options,
+                        &job.progress(),
+                    )
+                    .await?;
+                    session::start(
+                        state.sessions.clone(),
+                        state.new_heartbeat_sender.clone(),
+                        job.udid.clone(),
+                        ip,
+                        bundle_id.clone(),
+                        pid,
+                        debugserver,
+                    );
+                    Ok(pid)
+                }
+                JobKind::Launch { bundle_id, options } => {
+                    launch::launch_app(&job.udid, &job.ip, bundle_id, options, &job.progress())
+                        .await
+                }
+                JobKind::Attach(target) => {
+                    launch::attach(&job.udid, &job.ip, target, &job.progress()).await
+                }
+            }
+        })
+    }
+}
+
+/// Runs src/runners/launch.py once per launch, with netmuxd tracking the device meanwhile.
+/// It prints `[PID] <pid>` on success, or `[ERROR] <message>` and exits non-zero.
+/// Its `[INFO]`, `[WARN]` and `[ERROR]` lines are logged as the worker's.
+pub struct Python {
+    script: String,
+}
+
+impl LaunchBackend for Python {
+    fn name(&self) -> &'static str {
+        "python"
+    }
+
+    fn supports(&self, job: &LaunchJob) -> bool {
+        matches!(&job.kind, JobKind::Launch { options, .. } if !options.persistent)
+    }
+
+    fn run<'a>(
+        &'a self,
+        _state: &'a JitStreamerState,
+        worker: u32,
+        job: &'a LaunchJob,
+    ) -> LaunchFuture<'a> {
+        Box::pin(async move {
+            let (bundle_id, options) = match &job.kind {
+                JobKind::Launch { bundle_id, options } => (bundle_id, options),
+                JobKind::Attach(_) => {
+                    return Err(LaunchError::Python(
+                        "attaching is not supported".to_string(),
+                    ))
+                }
+            };
+
+            // Held until the script is done, or this is dropped and kills it
+            let _hold = launch::hold_device(&job.udid, &job.ip)
+                .await
+                .ok_or(LaunchError::Netmuxd)?;
+            let mut child = tokio::process::Command::new("python3")
+                .args([
+                    "-u",
+                    &self.script,
+                    &job.udid,
+                    &job.ip,
+                    bundle_id,
+                    &serde_json::to_string(options).unwrap(),
+                ])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+                .map_err(|e| LaunchError::PythonCrashed(format!("failed to start python3: {e}")))?;
+            debug_server::set_status(job.claim(), JobStatus::Launching, None);
+
+            let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+            let mut stderr = BufReader::new(child.stderr.take().unwrap()).lines();
+            let mut pid = None;
+            let mut error = None;
+            // A traceback ends with the exception, which is what's worth reporting
+            let mut last_stderr = None;
+            let output = async {
+                let (mut stdout_done, mut stderr_done) = (false, false);
+                while !(stdout_done && stderr_done) {
+                    tokio::select! {
+                        line = stdout.next_line(), if !stdout_done => match line? {
+                            Some(line) => match log_line(worker, &line) {
+                                ShimLine::Pid(p) => pid = Some(p),
+                                ShimLine::Error(e) => error = Some(e),
+                                ShimLine::Other => {}
+                            },
+                            None => stdout_done = true,
+                        },
+                        line = stderr.next_line(), if !stderr_done => match line? {
+                            Some(line) => {
+                                warn!("Worker {worker} launch.py stderr: {line}");
+                                last_stderr = Some(line);
+                            }
+                            None => stderr_done = true,
+                        },
+                    }
+                }
+                child.wait().await
+            };
+            // The script gives up after LAUNCH_TIMEOUT itself, this is for when it hangs
+            let exit = match tokio::time::timeout(launch::LAUNCH_TIMEOUT * 2, output).await {
+                Ok(r) => r?,
+                Err(_) => return Err(LaunchError::Timeout),
+            };
+
+            match (exit.success(), pid, error) {
+                (true, Some(pid), _) => Ok(pid),
+                (_, _, Some(e)) => Err(LaunchError::Python(e)),
+                _ => Err(LaunchError::PythonCrashed(match last_stderr {
+                    Some(line) => format!("launch.py exited with {exit}: {line}"),
+                    None => format!("launch.py exited with {exit}"),
+                })),
+            }
+        })
+    }
+}
+
+enum ShimLine {
+    Pid(u64),
+    Error(String),
+    Other,
+}
+
+/// Turns a line of launch.py's stdout into a log record for the worker
+fn log_line(worker: u32, line: &str) -> ShimLine {
+    if let Some(pid) = line.strip_prefix("[PID] ") {
+        match pid.trim().parse() {
+            Ok(pid) => return ShimLine::Pid(pid),
+            Err(_) => warn!("Worker {worker} launch.py gave a bad PID: {pid}"),
+        }
+    } else if let Some(msg) = line.strip_prefix("[INFO] ") {
+        info!("Worker {worker} launch.py: {msg}");
+    } else if let Some(msg) = line.strip_prefix("[WARN] ") {
+        warn!("Worker {worker} launch.py: {msg}");
+    } else if let Some(msg) = line.strip_prefix("[ERROR] ") {
+        error!("Worker {worker} launch.py: {msg}");
+        return ShimLine::Error(msg.to_string());
+    } else {
+        debug!("Worker {worker} launch.py: {line}");
+    }
+    ShimLine::Other
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RemoteLaunchRequest {
+    udid: String,
+    ip: String,
+    bundle_id: String,
+    #[serde(default)]
+    options: LaunchOptions,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RemoteLaunchReturn {
+    ok: bool,
+    pid: Option<u64>,
+    error: Option<String>,
+    error_code: Option<String>,
+}
+
+/// Sends the launch to another JitStreamer server's /worker/launch.
+/// That server needs the device's pairing file and a route to its VPN address.
+pub struct Remote {
+    url: String,
+    secret: String,
+    client: reqwest::Client,
+}
+
+impl LaunchBackend for Remote {
+    fn name(&self) -> &'static str {
+        "remote"
+    }
+
+    /// Jobs forwarded here run on this server, or they could go round in a loop
+    fn supports(&self, job: &LaunchJob) -> bool {
+        matches!(&job.kind, JobKind::Launch { options, .. } if !options.persistent && !options.forwarded)
+    }
+
+    fn run<'a>(
+        &'a self,
+        _state: &'a JitStreamerState,
+        _worker: u32,
+        job: &'a LaunchJob,
+    ) -> LaunchFuture<'a> {
+        Box::pin(async move {
+            let (bundle_id, options) = match &job.kind {
+                JobKind::Launch { bundle_id, options } => (bundle_id, options),
+                JobKind::Attach(_) => {
+                    return Err(LaunchError::Remote(
+                        "attaching is not supported".to_string(),
+                    ))
+                }
+            };
+
+            let req = self
+                .client
+                .post(format!("{}/worker/launch", self.url.trim_end_matches('/')))
+                .timeout(REMOTE_TIMEOUT)
+                .bearer_auth(&self.secret)
+                .json(&RemoteLaunchRequest {
+                    udid: job.udid.clone(),
+                    ip: job.ip.clone(),
+                    bundle_id: bundle_id.clone(),
+                    options: options.clone(),
+                });
+            debug_server::set_status(job.claim(), JobStatus::Launching, None);
+
+            let res = req
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| LaunchError::Remote(e.to_string()))?
+                .json::<RemoteLaunchReturn>()
+                .await
+                .map_err(|e| LaunchError::Remote(e.to_string()))?;
+            match res {
+                RemoteLaunchReturn {
+                    ok: true,
+                    pid: Some(pid),
+                    ..
+                } => Ok(pid),
+                RemoteLaunchReturn {
+                    error, error_code, ..
+                } => Err(LaunchError::Remote(format!(
+                    "{} ({})",
+                    error.unwrap_or_default(),
+                    error_code.unwrap_or_default()
+                ))),
+            }
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct BackendStats {
+    succeeded: u64,
+    failed: u64,
+}
+
+/// The configured backends and how each one has done
+pub struct Backends {
+    primary: Box<dyn LaunchBackend>,
+    canary: Option<Box<dyn LaunchBackend>>,
+    canary_percent: u8,
+    stats: Mutex<BTreeMap<&'static str, BackendStats>>,
+}
+
+impl Backends {
+    pub fn from_env() -> Result<Self, String> {
+        let primary =
+            backend_from_name(&std::env::var("LAUNCH_BACKEND").unwrap_or("native".to_string()))?;
+        let canary = match std::env::var("LAUNCH_CANARY_BACKEND") {
+            Ok(name) if !name.is_empty() => Some(backend_from_name(&name)?),
+            _ => None,
+        };
+        let canary_percent = std::env::var("LAUNCH_CANARY_PERCENT")
+            .unwrap_or("0".to_string())
+            .parse::<u8>()
+            .map_err(|_| "LAUNCH_CANARY_PERCENT must be 0 to 100".to_string())?
+            .min(100);
+        info!(
+            "Launching with the {} backend, {canary_percent}% to {}",
+            primary.name(),
+            canary.as_ref().map(|c| c.name()).unwrap_or("no canary")
+        );
+        Ok(Self {
+            primary,
+            canary,
+            canary_percent,
+            stats: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Picks the backend for a job. Canary jobs are spread evenly by job ID.
+    pub fn pick(&self, job: &LaunchJob) -> &dyn LaunchBackend {
+        let backend = match &self.canary {
+            Some(c) if job.ordinal.rem_euclid(100) < self.canary_percent as i64 => c.as_ref(),
+            _ => self.primary.as_ref(),
+        };
+        if backend.supports(job) {
+            backend
+        } else {
+            &Native
+        }
+    }
+
+    pub fn record(&self, backend: &'static str, succeeded: bool) {
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(backend).or_default();
+        if succeeded {
+            stats.succeeded += 1;
+        } else {
+            stats.failed += 1;
+        }
+    }
+
+    pub fn stats(&self) -> BTreeMap<&'static str, BackendStats> {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Whether launches go through this server's tunneld
+    pub fn uses_tunneld(&self) -> bool {
+        let uses = |b: &dyn LaunchBackend| match b.name() {
+            "remote" => false,
+            "python" => true,
+            _ => launch::tunnel_mode() == TunnelMode::Tunneld,
+        };
+        uses(self.primary.as_ref()) || self.canary.as_deref().is_some_and(uses)
+    }
+
+    pub fn primary(&self) -> &'static str {
+        self.primary.name()
+    }
+
+    pub fn canary(&self) -> Option<(&'static str, u8)> {
+        self.canary
+            .as_ref()
+            .map(|c| (c.name(), self.canary_percent))
+    }
+}
+
+fn backend_from_name(name: &str) -> Result<Box<dyn LaunchBackend>, String> {
+    match name {
+        "native" => Ok(Box::new(Native)),
+        "python" => {
+            let script = std::env::var("PYTHON_LAUNCH_SCRIPT")
+                .unwrap_or("src/runners/launch.py".to_string());
+            // The Docker image doesn't ship Python, fail at startup rather than on every launch
+            if !on_path("python3") {
+                return Err("The python backend needs python3, which isn't installed".to_string());
+            }
+            if !std::path::Path::new(&script).is_file() {
+                return Err(format!(
+                    "The python backend's script {script} doesn't exist"
+                ));
+            }
+            Ok(Box::new(Python { script }))
+        }
+        "remote" => Ok(Box::new(Remote {
+            url: std::env::var("REMOTE_LAUNCH_URL")
+                .map_err(|_| "REMOTE_LAUNCH_URL is required for the remote backend")?,
+            secret: std::env::var(SECRET_VAR)
+                .map_err(|_| "WORKER_SECRET is required for the remote backend")?,
+            client: reqwest::Client::new(),
+        })),
+        _ => Err(format!("Unknown launch backend {name}")),
+    }
+}
+
+fn on_path(program: &str) -> bool {
+    std::env::var_os("PATH")
+        .is_some_and(|paths| std::env::split_paths(&paths).any(|p| p.join(program).is_file()))
+}
+
+/// Cancels the local job if the server that sent it gives up waiting
+struct CancelOnDrop(Option<(JitStreamerState, i64)>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some((state, job_id)) = self.0.take() {
+            tokio::task::spawn(async move {
+                runner::cancel(&state.cancellations, job_id).await;
+            });
+        }
+    }
+}
+
+/// Takes a launch from another server's remote backend and runs it through this server's queue
+pub async fn remote_launch(
+    headers: HeaderMap,
+    State(state): State<JitStreamerState>,
+    Json(req): Json<RemoteLaunchRequest>,
+) -> Result<Json<RemoteLaunchReturn>, (StatusCode, &'static str)> {
+    state.worker_secret.check(&headers)?;
+    info!("Got remote launch of {} for {}", req.bundle_id, req.udid);
+    if req.ip.parse::<IpAddr>().is_err() {
+        return Err((StatusCode::BAD_REQUEST, "bad ip"));
+    }
+    // Sessions stay on the server the device talks to
+    if req.options.persistent {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "persistent launches can't be forwarded",
+        ));
+    }
+
+    let (job_id, _) = debug_server::add_to_queue(
+        &req.udid,
+        req.ip,
+        &JobKind::Launch {
+            bundle_id: req.bundle_id,
+            options: LaunchOptions {
+                forwarded: true,
+                ..req.options
+            },
+        },
+    );
+    let mut guard = CancelOnDrop(Some((state, job_id)));
+    let mut job = match debug_server::watch_job(job_id) {
+        Some(j) => j,
+        None => return Err((StatusCode::INTERNAL_SERVER_ERROR, "job disappeared")),
+    };
+    let info = match job.wait_for(|j| j.finished_at.is_some()).await {
+        Ok(j) => j.clone(),
+        Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "job disappeared")),
+    };
+    guard.0 = None;
+
+    Ok(Json(match info.status {
+        JobStatus::Detached | JobStatus::Attached => RemoteLaunchReturn {
+            ok: true,
+            pid: info.pid,
+            error: None,
+            error_code: None,
+        },
+        _ => RemoteLaunchReturn {
+            ok: false,
+            pid: None,
+            error: Some(info.error.unwrap_or("Cancelled".to_string())),
+            error_code: info.error_code,
+        },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_launcher_lines() {
+        assert!(matches!(log_line(0, "[PID] 1234"), ShimLine::Pid(1234)));
+        assert!(matches!(log_line(0, "[PID]  1234 "), ShimLine::Pid(1234)));
+        assert!(matches!(log_line(0, "[PID] soon"), ShimLine::Other));
+        assert!(
+            matches!(log_line(0, "[ERROR] No tunnel found"), ShimLine::Error(e) if e == "No tunnel found")
+        );
+        assert!(matches!(log_line(0, "[INFO] Launching"), ShimLine::Other));
+        assert!(matches!(log_line(0, "[WARN] Retrying"), ShimLine::Other));
+        assert!(matches!(log_line(0, "PID 1234"), ShimLine::Other));
+    }
+
+    #[test]
+    fn keeps_forwarded_jobs_here() {
+        let backends = Backends {
+            primary: Box::new(Remote {
+                url: "http://127.0.0.1:9172".to_string(),
+                secret: "secret".to_string(),
+                client: reqwest::Client::new(),
+            }),
+            canary: None,
+            canary_percent: 0,
+            stats: Mutex::new(BTreeMap::new()),
+        };
+        let job = |options| LaunchJob {
+            udid: "udid".to_string(),
+            ip: "10.7.0.2".to_string(),
+            kind: JobKind::Launch {
+                bundle_id: "com.example.app".to_string(),
+                options,
+            },
+            ordinal: 1,
+            attempt: 1,
+        };
+
+        assert_eq!(
+            backends.pick(&job(LaunchOptions::default())).name(),
+            "remote"
+        );
+        let forwarded = LaunchOptions {
+            forwarded: true,
+            ..Default::default()
+        };
+        assert_eq!(backends.pick(&job(forwarded)).name(), "native");
+        let persistent = LaunchOptions {
+            persistent: true,
+            ..Default::default()
+        };
+        assert_eq!(backends.pick(&job(persistent)).name(), "native");
+    }
+
+    #[test]
+    fn rejects_unknown_backends() {
+        assert!(backend_from_name("native").is_ok());
+        assert!(backend_from_name("pymobiledevice3").is_err());
+    }
+}
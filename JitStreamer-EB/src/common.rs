@@ -1,8 +1,34 @@
 // Jackson Coxson
 
+use axum::http::{header::AUTHORIZATION, HeaderMap, StatusCode};
 use idevice::pairing_file::PairingFile;
 use log::info;
 
+/// Checks for `Authorization: Bearer <token>` against the token in the env var.
+/// The endpoint doesn't exist if the env var isn't set.
+pub fn check_bearer(headers: &HeaderMap, var: &str) -> Result<(), (StatusCode, &'static str)> {
+    check_token(headers, std::env::var(var).ok().as_deref())
+}
+
+/// Same as `check_bearer`, for a token that was read ahead of time
+pub fn check_token(
+    headers: &HeaderMap,
+    token: Option<&str>,
+) -> Result<(), (StatusCode, &'static str)> {
+    let token = match token {
+        Some(t) if !t.is_empty() => t,
+        _ => return Err((StatusCode::NOT_FOUND, "endpoint is disabled")),
+    };
+    match headers
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+    {
+        Some(t) if t == token => Ok(()),
+        _ => Err((StatusCode::UNAUTHORIZED, "bad token")),
+    }
+}
+
 pub async fn get_udid_from_ip(ip: String) -> Result<String, String> {
     tokio::task::spawn_blocking(move || {
         let db = match sqlite::open("jitstreamer.db") {
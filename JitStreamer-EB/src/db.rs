@@ -26,3 +26,97 @@ pub fn statement_next(statement: &mut Statement) -> Option<State> {
     }
     None
 }
+
+/// Schema changes on top of up.sql, in order. PRAGMA user_version holds how many have run.
+const MIGRATIONS: &[&str] = &[
+    include_str!("sql/migrations/001_launch_options.sql"),
+    include_str!("sql/migrations/002_attach_jobs.sql"),
+    include_str!("sql/migrations/003_persistent_sessions.sql"),
+    include_str!("sql/migrations/004_job_lifecycle.sql"),
+    include_str!("sql/migrations/005_job_leases.sql"),
+    include_str!("sql/migrations/006_forwarded_jobs.sql"),
+];
+
+pub fn migrate(db: &Connection) -> Result<(), String> {
+    run_migrations(db, MIGRATIONS)
+}
+
+/// Runs each migration not run yet in its own transaction, so a failed one leaves nothing behind
+fn run_migrations(db: &Connection, migrations: &[&str]) -> Result<(), String> {
+    let mut statement = db
+        .prepare("PRAGMA user_version")
+        .map_err(|e| format!("failed to read the schema version: {e}"))?;
+    let version = match statement_next(&mut statement) {
+        Some(State::Row) => statement.read::<i64, _>(0).unwrap_or(0) as usize,
+        _ => 0,
+    };
+    std::mem::drop(statement);
+
+    for (i, migration) in migrations.iter().enumerate().skip(version) {
+        log::info!("Running database migration {}", i + 1);
+        let res = db.execute("BEGIN").and_then(|_| {
+            db.execute(migration)?;
+            db.execute(format!("PRAGMA user_version = {}", i + 1))?;
+            db.execute("COMMIT")
+        });
+        if let Err(e) = res {
+            db.execute("ROLLBACK").ok();
+            return Err(format!("database migration {} failed: {e}", i + 1));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrates_once() {
+        let db = sqlite::open(":memory:").unwrap();
+        db.execute(include_str!("sql/up.sql")).unwrap();
+        migrate(&db).unwrap();
+        // Nothing left to run the second time
+        migrate(&db).unwrap();
+
+        let mut statement = db.prepare("PRAGMA user_version").unwrap();
+        assert_eq!(statement_next(&mut statement), Some(State::Row));
+        assert_eq!(
+            statement.read::<i64, _>(0).unwrap(),
+            MIGRATIONS.len() as i64
+        );
+        std::mem::drop(statement);
+
+        // The migrated columns are all there
+        db.execute(
+            "insert into launch_queue (udid, ip, bundle_id, status, arguments, environment, \
+             kill_existing, kind, pid, persistent, created_at, lease_expires, attempts, forwarded) \
+             values ('udid', '10.7.0.2', 'com.example.app', 0, '[]', '{}', 1, 1, 5, 1, \
+             datetime('now'), null, 0, 1)",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn rolls_back_failed_migrations() {
+        let db = sqlite::open(":memory:").unwrap();
+        let migrations = [
+            "create table a (x int);",
+            "create table b (x int); alter table missing add column y int;",
+        ];
+        assert!(run_migrations(&db, &migrations).is_err());
+
+        // The first stuck, none of the second did
+        let mut statement = db.prepare("PRAGMA user_version").unwrap();
+        assert_eq!(statement_next(&mut statement), Some(State::Row));
+        assert_eq!(statement.read::<i64, _>(0).unwrap(), 1);
+        std::mem::drop(statement);
+        assert!(db.execute("insert into a (x) values (1)").is_ok());
+        assert!(db.execute("insert into b (x) values (1)").is_err());
+
+        // It runs again once it's fixed
+        let migrations = [migrations[0], "create table b (x int);"];
+        run_migrations(&db, &migrations).unwrap();
+        assert!(db.execute("insert into b (x) values (1)").is_ok());
+    }
+}
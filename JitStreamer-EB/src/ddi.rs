@@ -0,0 +1,426 @@
+// Jackson Coxson
+// Developer disk images loaded from disk, so new ones don't need a rebuild.
+// Each subdirectory of DDI_DIRECTORY is a version with its own manifest, image and trustcache.
+// Files directly in DDI_DIRECTORY are loaded as the version named "default".
+// Subdirectories with a DeveloperDiskImage.dmg and its signature are legacy images for
+// iOS 16 and earlier, named by the iOS version they're for.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use bytes::Bytes;
+use log::{info, warn};
+use serde::Serialize;
+use sha2::Digest;
+
+use crate::debug_server;
+
+const DEFAULT_DIRECTORY: &str = "DDI";
+const DEFAULT_NAME: &str = "default";
+const MANIFEST_FILE: &str = "BuildManifest.plist";
+const IMAGE_FILE: &str = "Image.dmg";
+const TRUSTCACHE_FILE: &str = "Image.dmg.trustcache";
+const LEGACY_IMAGE_FILE: &str = "DeveloperDiskImage.dmg";
+const LEGACY_SIGNATURE_FILE: &str = "DeveloperDiskImage.dmg.signature";
+
+/// A chip ID and board ID, which the manifest signs the image for
+type Identity = (u64, u64);
+
+/// Loaded once and shared by every mount, uploads read straight from these buffers
+pub struct DdiImage {
+    pub name: String,
+    pub manifest: Bytes,
+    pub image: Bytes,
+    pub trustcache: Bytes,
+    /// The ProductVersion from the manifest, if it has one
+    pub version: Option<String>,
+    /// The devices the manifest has build identities for
+    pub identities: Vec<Identity>,
+    /// SHA-384 of the image, which the device knows it by
+    pub hash: Vec<u8>,
+}
+
+impl DdiImage {
+    fn supports(&self, chip_id: u64, board_id: u64) -> bool {
+        self.identities.contains(&(chip_id, board_id))
+    }
+}
+
+/// A developer disk image signed by Apple for every device on an iOS version
+pub struct LegacyDdi {
+    /// The iOS version, like 16.4
+    pub version: String,
+    pub image: Bytes,
+    pub signature: Bytes,
+    /// SHA-384 of the image
+    pub hash: Vec<u8>,
+}
+
+enum Loaded {
+    Personalized(DdiImage),
+    Legacy(LegacyDdi),
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DdiKind {
+    Personalized,
+    Legacy,
+}
+
+#[derive(Serialize)]
+pub struct DdiInfo {
+    name: String,
+    kind: DdiKind,
+    version: Option<String>,
+    image_size: usize,
+    /// Only personalized images have these
+    trustcache_size: Option<usize>,
+    identities: Option<usize>,
+    sha384: String,
+}
+
+pub struct DdiStore {
+    directory: PathBuf,
+    /// Newest version first
+    images: RwLock<Vec<Arc<DdiImage>>>,
+    /// Newest version first
+    legacy: RwLock<Vec<Arc<LegacyDdi>>>,
+    loaded_at: RwLock<Option<String>>,
+}
+
+impl DdiStore {
+    /// Uses DDI_DIRECTORY, or DDI in the working directory. Call `reload` to load the images.
+    pub fn from_env() -> Self {
+        Self {
+            directory: std::env::var("DDI_DIRECTORY")
+                .unwrap_or(DEFAULT_DIRECTORY.to_string())
+                .into(),
+            images: RwLock::new(Vec::new()),
+            legacy: RwLock::new(Vec::new()),
+            loaded_at: RwLock::new(None),
+        }
+    }
+
+    /// Loads every version in the directory, replacing what's held.
+    /// Keeps the old images if the directory can't be read.
+    pub async fn reload(&self) -> Result<usize, String> {
+        let mut versions = vec![(self.directory.clone(), DEFAULT_NAME.to_string())];
+        let mut entries = tokio::fs::read_dir(&self.directory)
+            .await
+            .map_err(|e| format!("failed to read {}: {e}", self.directory.display()))?;
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if entry.file_type().await.is_ok_and(|t| t.is_dir()) {
+                versions.push((
+                    entry.path(),
+                    entry.file_name().to_string_lossy().to_string(),
+                ));
+            }
+        }
+
+        let mut images = Vec::new();
+        let mut legacy = Vec::new();
+        for (directory, name) in versions {
+            match load(&directory, &name).await {
+                Ok(Some(Loaded::Personalized(image))) => images.push(image),
+                Ok(Some(Loaded::Legacy(image))) => legacy.push(image),
+                Ok(None) => {}
+                // One bad version shouldn't take the rest down with it
+                Err(e) => warn!("Skipping developer disk image {name}: {e}"),
+            }
+        }
+
+        images.sort_by_key(|i| {
+            std::cmp::Reverse(version_key(i.version.as_deref().unwrap_or(&i.name)))
+        });
+        legacy.sort_by_key(|i| std::cmp::Reverse(version_key(&i.version)));
+        let count = images.len() + legacy.len();
+        info!(
+            "Loaded {count} developer disk images from {}: {}",
+            self.directory.display(),
+            images
+                .iter()
+                .map(|i| i.name.as_str())
+                .chain(legacy.iter().map(|i| i.version.as_str()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        *self.images.write().unwrap() = images.into_iter().map(Arc::new).collect();
+        *self.legacy.write().unwrap() = legacy.into_iter().map(Arc::new).collect();
+        *self.loaded_at.write().unwrap() = Some(debug_server::timestamp());
+        Ok(count)
+    }
+
+    /// The newest image with a build identity for the device
+    pub fn pick(&self, chip_id: u64, board_id: u64) -> Option<Arc<DdiImage>> {
+        self.images
+            .read()
+            .unwrap()
+            .iter()
+            .find(|i| i.supports(chip_id, board_id))
+            .cloned()
+    }
+
+    /// The legacy image for an iOS version. Without one for the exact version,
+    /// the newest one for an earlier release of the same major version.
+    pub fn pick_legacy(&self, product_version: &str) -> Option<Arc<LegacyDdi>> {
+        let wanted = version_key(product_version);
+        let major_minor = &wanted[..wanted.len().min(2)];
+        let legacy = self.legacy.read().unwrap();
+        legacy
+            .iter()
+            .find(|i| version_key(&i.version) == major_minor)
+            .or_else(|| {
+                legacy.iter().find(|i| {
+                    let key = version_key(&i.version);
+                    key.first() == wanted.first() && key <= wanted
+                })
+            })
+            .cloned()
+    }
+
+    pub fn list(&self) -> Vec<DdiInfo> {
+        let images = self.images.read().unwrap();
+        let legacy = self.legacy.read().unwrap();
+        images
+            .iter()
+            .map(|i| DdiInfo {
+                name: i.name.clone(),
+                kind: DdiKind::Personalized,
+                version: i.version.clone(),
+                image_size: i.image.len(),
+                trustcache_size: Some(i.trustcache.len()),
+                identities: Some(i.identities.len()),
+                sha384: hex(&i.hash),
+            })
+            .chain(legacy.iter().map(|i| DdiInfo {
+                name: i.version.clone(),
+                kind: DdiKind::Legacy,
+                version: Some(i.version.clone()),
+                image_size: i.image.len(),
+                trustcache_size: None,
+                identities: None,
+                sha384: hex(&i.hash),
+            }))
+            .collect()
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub fn loaded_at(&self) -> Option<String> {
+        self.loaded_at.read().unwrap().clone()
+    }
+}
+
+/// Loads the version in the directory, or None if it doesn't have one
+async fn load(directory: &Path, name: &str) -> Result<Option<Loaded>, String> {
+    let read = |file: &str| {
+        let path = directory.join(file);
+        async move {
+            tokio::fs::read(&path)
+                .await
+                .map(Bytes::from)
+                .map_err(|e| format!("failed to read {}: {e}", path.display()))
+        }
+    };
+    let exists = |file: &str| {
+        let path = directory.join(file);
+        async move { tokio::fs::try_exists(path).await.unwrap_or(false) }
+    };
+    if !exists(MANIFEST_FILE).await {
+        if !exists(LEGACY_IMAGE_FILE).await {
+            return Ok(None);
+        }
+        if version_key(name).is_empty() {
+            return Err("legacy images need to be named by their iOS version".to_string());
+        }
+        let image = read(LEGACY_IMAGE_FILE).await?;
+        return Ok(Some(Loaded::Legacy(LegacyDdi {
+            version: name.to_string(),
+            hash: sha2::Sha384::digest(&image).to_vec(),
+            image,
+            signature: read(LEGACY_SIGNATURE_FILE).await?,
+        })));
+    }
+
+    let manifest = read(MANIFEST_FILE).await?;
+    let image = read(IMAGE_FILE).await?;
+    let trustcache = read(TRUSTCACHE_FILE).await?;
+    let (version, identities) = parse_manifest(&manifest)?;
+    if identities.is_empty() {
+        return Err("the manifest has no build identities".to_string());
+    }
+
+    Ok(Some(Loaded::Personalized(DdiImage {
+        name: name.to_string(),
+        hash: sha2::Sha384::digest(&image).to_vec(),
+        manifest,
+        image,
+        trustcache,
+        version,
+        identities,
+    })))
+}
+
+/// Gets the version and the chip and board ID of each build identity
+fn parse_manifest(manifest: &[u8]) -> Result<(Option<String>, Vec<Identity>), String> {
+    let manifest = plist::from_bytes::<plist::Dictionary>(manifest)
+        .map_err(|e| format!("failed to parse the manifest: {e}"))?;
+    let version = manifest
+        .get("ProductVersion")
+        .and_then(|v| v.as_string())
+        .map(String::from);
+
+    let identities = manifest
+        .get("BuildIdentities")
+        .and_then(|v| v.as_array())
+        .map(|a| a.as_slice())
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|identity| {
+            let identity = identity.as_dictionary()?;
+            Some((
+                parse_id(identity.get("ApChipID")?)?,
+                parse_id(identity.get("ApBoardID")?)?,
+            ))
+        })
+        .collect();
+    Ok((version, identities))
+}
+
+/// Manifests write IDs as hex strings, like 0x8101
+fn parse_id(value: &plist::Value) -> Option<u64> {
+    match value {
+        plist::Value::String(s) => u64::from_str_radix(s.trim_start_matches("0x"), 16).ok(),
+        v => v.as_unsigned_integer(),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Sorts 17.10 after 17.4
+fn version_key(version: &str) -> Vec<u64> {
+    version
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|p| !p.is_empty())
+        .map(|p| p.parse().unwrap_or(0))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(version: &str, identities: &[(&str, &str)]) -> Vec<u8> {
+        let mut dict = plist::Dictionary::new();
+        dict.insert("ProductVersion".into(), version.into());
+        dict.insert(
+            "BuildIdentities".into(),
+            plist::Value::Array(
+                identities
+                    .iter()
+                    .map(|(chip, board)| {
+                        let mut identity = plist::Dictionary::new();
+                        identity.insert("ApChipID".into(), (*chip).into());
+                        identity.insert("ApBoardID".into(), (*board).into());
+                        identity.into()
+                    })
+                    .collect(),
+            ),
+        );
+        let mut buf = Vec::new();
+        plist::to_writer_xml(&mut buf, &dict).unwrap();
+        buf
+    }
+
+    async fn write_version(dir: &Path, manifest: Vec<u8>, image: &[u8]) {
+        tokio::fs::create_dir_all(dir).await.unwrap();
+        tokio::fs::write(dir.join(MANIFEST_FILE), manifest)
+            .await
+            .unwrap();
+        tokio::fs::write(dir.join(IMAGE_FILE), image).await.unwrap();
+        tokio::fs::write(dir.join(TRUSTCACHE_FILE), b"trustcache")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn loads_and_picks_versions() {
+        let dir = std::env::temp_dir().join(format!("jitstreamer-ddi-{}", std::process::id()));
+        tokio::fs::remove_dir_all(&dir).await.ok();
+        write_version(
+            &dir,
+            manifest("17.4", &[("0x8101", "0x0C"), ("0x8110", "0x0E")]),
+            b"old",
+        )
+        .await;
+        write_version(
+            &dir.join("17.10"),
+            manifest("17.10", &[("0x8101", "0x0C")]),
+            b"new",
+        )
+        .await;
+        for version in ["16.4", "16.1", "15.7"] {
+            let legacy = dir.join(version);
+            tokio::fs::create_dir_all(&legacy).await.unwrap();
+            tokio::fs::write(legacy.join(LEGACY_IMAGE_FILE), version)
+                .await
+                .unwrap();
+            tokio::fs::write(legacy.join(LEGACY_SIGNATURE_FILE), b"signature")
+                .await
+                .unwrap();
+        }
+        // Missing its image, skipped
+        tokio::fs::create_dir_all(dir.join("broken")).await.unwrap();
+        tokio::fs::write(
+            dir.join("broken").join(MANIFEST_FILE),
+            manifest("18.0", &[]),
+        )
+        .await
+        .unwrap();
+
+        let store = DdiStore {
+            directory: dir.clone(),
+            images: RwLock::new(Vec::new()),
+            legacy: RwLock::new(Vec::new()),
+            loaded_at: RwLock::new(None),
+        };
+        assert_eq!(store.reload().await, Ok(5));
+        assert!(store.loaded_at().is_some());
+
+        let list = store.list();
+        assert_eq!(list[0].name, "17.10");
+        assert_eq!(list[1].name, DEFAULT_NAME);
+        assert_eq!(list[1].identities, Some(2));
+        assert_eq!(list[2].name, "16.4");
+        assert_eq!(list[2].identities, None);
+
+        let newest = store.pick(0x8101, 0x0C).unwrap();
+        assert_eq!(newest.name, "17.10");
+        assert_eq!(newest.hash, sha2::Sha384::digest(b"new").to_vec());
+        // Every mount shares the one copy
+        assert!(Arc::ptr_eq(&newest, &store.pick(0x8101, 0x0C).unwrap()));
+        assert_eq!(store.pick(0x8110, 0x0E).unwrap().name, DEFAULT_NAME);
+        assert!(store.pick(0x8120, 0x0C).is_none());
+
+        let legacy = store.pick_legacy("16.4.1").unwrap();
+        assert_eq!(legacy.version, "16.4");
+        assert_eq!(legacy.image, &b"16.4"[..]);
+        assert_eq!(legacy.signature, &b"signature"[..]);
+        assert_eq!(store.pick_legacy("16.2").unwrap().version, "16.1");
+        assert_eq!(store.pick_legacy("15.8").unwrap().version, "15.7");
+        assert!(store.pick_legacy("16.0").is_none());
+        assert!(store.pick_legacy("14.8").is_none());
+
+        // A reload that fails keeps what was loaded
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+        assert!(store.reload().await.is_err());
+        assert_eq!(store.list().len(), 5);
+    }
+}
@@ -1,185 +1,1178 @@
 // Jackson Coxson
-// Until a Rust implementation is written, we're gonna use a queue system to interface with a
-// Python runner or two.
-// See rsd.rs for my rant
+// Queue of launches waiting for a launch worker, see runner.rs.
+// The queue lives in memory, every change is written behind to launch_queue
+// so jobs survive a restart.
 
-use log::debug;
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
 use sqlite::State;
+use tokio::sync::{mpsc, watch, Notify};
+
+use crate::launch::AttachTarget;
+
+/// How long a claim lasts without the worker renewing it
+pub const LEASE_DURATION: Duration = Duration::from_secs(30);
+/// How long finished jobs are kept for /status/{job_id}
+const FINISHED_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+static QUEUE: LazyLock<Mutex<Queue>> = LazyLock::new(|| Mutex::new(Queue::default()));
+static PERSIST: LazyLock<mpsc::UnboundedSender<Persist>> = LazyLock::new(persist_thread);
+/// Bumped whenever a job is added or moves along, so status streams know to look again
+static QUEUE_CHANGES: LazyLock<watch::Sender<u64>> = LazyLock::new(|| watch::channel(0).0);
+/// Wakes a waiting worker when there's a job to claim
+static JOB_AVAILABLE: Notify = Notify::const_new();
+/// Tests that go through the global queue take turns, so they don't claim each other's jobs
+#[cfg(test)]
+pub static GLOBAL_QUEUE_TEST: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+pub fn subscribe() -> watch::Receiver<u64> {
+    QUEUE_CHANGES.subscribe()
+}
+
+fn changed() {
+    QUEUE_CHANGES.send_modify(|v| *v += 1);
+}
 
 pub enum LaunchQueueInfo {
     Position(usize),
     NotInQueue,
     Error(String),
-    ServerError,
 }
 
-// create table launch_queue (
-//   udid varchar(40) not null,
-//   bundle_id varchar(255) not null,
-//   status int not null, -- 0: pending, 2: error
-//   error varchar(255),
-//   ordinal int primary key
-// );
+/// Extra knobs passed through to process control when the app is launched
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct LaunchOptions {
+    #[serde(default)]
+    pub arguments: Vec<String>,
+    #[serde(default)]
+    pub environment: HashMap<String, String>,
+    #[serde(default)]
+    pub kill_existing: bool,
+    /// Stay attached after launching and handle the app's stops, see session.rs
+    #[serde(default)]
+    pub persistent: bool,
+    /// Sent by another server's remote backend, so it's never sent on again
+    #[serde(skip)]
+    pub forwarded: bool,
+}
 
-pub async fn get_queue_info(udid: &str) -> LaunchQueueInfo {
-    let udid = udid.to_string();
-    tokio::task::spawn_blocking(move || {
-        let db = match sqlite::open("jitstreamer.db") {
-            Ok(db) => db,
-            Err(e) => {
-                log::error!("Failed to open database: {:?}", e);
-                return LaunchQueueInfo::ServerError;
+/// What a worker should do once it has the device
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    Launch {
+        bundle_id: String,
+        options: LaunchOptions,
+    },
+    Attach(AttachTarget),
+}
+
+impl std::fmt::Display for JobKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobKind::Launch { bundle_id, .. } => write!(f, "launch {bundle_id}"),
+            JobKind::Attach(AttachTarget::Pid(pid)) => write!(f, "attach to PID {pid}"),
+            JobKind::Attach(AttachTarget::Name(name)) => write!(f, "attach to {name}"),
+        }
+    }
+}
+
+/// Where a job is in its lifecycle, stored in the status column
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued = 0,
+    Claimed = 1,
+    Failed = 2,
+    Launching = 3,
+    Attached = 4,
+    Detached = 5,
+    Cancelled = 6,
+}
+
+impl JobStatus {
+    fn from_i64(status: i64) -> Option<Self> {
+        Some(match status {
+            0 => JobStatus::Queued,
+            1 => JobStatus::Claimed,
+            2 => JobStatus::Failed,
+            3 => JobStatus::Launching,
+            4 => JobStatus::Attached,
+            5 => JobStatus::Detached,
+            6 => JobStatus::Cancelled,
+            _ => return None,
+        })
+    }
+}
+
+/// A job as reported by /status/{job_id}
+#[derive(Debug, Clone, Serialize)]
+pub struct JobInfo {
+    pub job_id: i64,
+    #[serde(skip)]
+    pub udid: String,
+    pub status: JobStatus,
+    pub position: Option<usize>,
+    pub bundle_id: Option<String>,
+    pub pid: Option<u64>,
+    pub error: Option<String>,
+    pub error_code: Option<String>,
+    pub attempts: i64,
+    pub created_at: Option<String>,
+    pub claimed_at: Option<String>,
+    pub updated_at: Option<String>,
+    pub finished_at: Option<String>,
+}
+
+pub struct LaunchJob {
+    pub udid: String,
+    pub ip: String,
+    pub kind: JobKind,
+    pub ordinal: i64,
+    /// Which attempt this claim is, only the latest claim may finish the job
+    pub attempt: i64,
+}
+
+impl LaunchJob {
+    pub fn claim(&self) -> Claim {
+        Claim {
+            ordinal: self.ordinal,
+            attempt: self.attempt,
+        }
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress::Local(self.claim())
+    }
+}
+
+/// A worker's claim on a job, which it reports progress under
+#[derive(Debug, Clone, Copy)]
+pub struct Claim {
+    pub ordinal: i64,
+    pub attempt: i64,
+}
+
+/// Where a worker reports how far it got with a job
+#[derive(Debug, Clone)]
+pub enum Progress {
+    /// Straight into this server's queue
+    Local(Claim),
+    /// Passed on to the server the job came from, see worker.rs
+    Remote(mpsc::UnboundedSender<(JobStatus, Option<u64>)>),
+}
+
+impl Progress {
+    pub fn set_status(&self, status: JobStatus, pid: Option<u64>) {
+        match self {
+            Progress::Local(claim) => set_status(*claim, status, pid),
+            Progress::Remote(updates) => {
+                updates.send((status, pid)).ok();
+            }
+        }
+    }
+}
+
+struct Job {
+    ip: String,
+    kind: JobKind,
+    acknowledged: bool,
+    lease_expires: Option<Instant>,
+    finished: Option<Instant>,
+    info: watch::Sender<JobInfo>,
+}
+
+#[derive(Default)]
+struct Queue {
+    jobs: HashMap<i64, Job>,
+    /// Queued jobs in the order they'll be claimed
+    pending: BTreeSet<i64>,
+    by_udid: HashMap<String, BTreeSet<i64>>,
+    last_ordinal: i64,
+}
+
+impl Queue {
+    fn insert(&mut self, ordinal: i64, job: Job) {
+        let udid = job.info.borrow().udid.clone();
+        if job.info.borrow().status == JobStatus::Queued {
+            self.pending.insert(ordinal);
+        }
+        self.by_udid.entry(udid).or_default().insert(ordinal);
+        self.last_ordinal = self.last_ordinal.max(ordinal);
+        self.jobs.insert(ordinal, job);
+    }
+
+    fn remove(&mut self, ordinal: i64) {
+        if let Some(job) = self.jobs.remove(&ordinal) {
+            let udid = job.info.borrow().udid.clone();
+            if let Some(jobs) = self.by_udid.get_mut(&udid) {
+                jobs.remove(&ordinal);
+                if jobs.is_empty() {
+                    self.by_udid.remove(&udid);
+                }
             }
+        }
+        self.pending.remove(&ordinal);
+        PERSIST.send(Persist::Delete(ordinal)).ok();
+    }
+
+    /// Applies the change to the job's info, writes it behind and tells the watchers
+    fn update(&mut self, ordinal: i64, f: impl FnOnce(&mut Job, &mut JobInfo)) {
+        let job = match self.jobs.get_mut(&ordinal) {
+            Some(j) => j,
+            None => return,
         };
+        let mut info = job.info.borrow().clone();
+        f(job, &mut info);
+        info.updated_at = Some(timestamp());
+        let moved = if info.status == JobStatus::Queued {
+            self.pending.insert(ordinal)
+        } else {
+            info.position = None;
+            self.pending.remove(&ordinal)
+        };
+        job.info.send_replace(info);
+        self.persist(ordinal);
+        // Only the jobs behind one that joined or left the queue move
+        if moved {
+            self.reposition(ordinal);
+        }
+        changed();
+    }
 
-        // Determine the status of the UDID
-        let query = "SELECT ordinal, status FROM launch_queue WHERE udid = ?";
-        let mut statement = match crate::db::db_prepare(&db, query) {
+    /// Hands out positions to the queued jobs from `from` on, so reading one is free
+    fn reposition(&self, from: i64) {
+        let start = self.pending.range(..from).count();
+        for (position, ordinal) in self.pending.range(from..).enumerate() {
+            let position = start + position;
+            if let Some(job) = self.jobs.get(ordinal) {
+                job.info.send_if_modified(|info| {
+                    if info.position == Some(position) {
+                        false
+                    } else {
+                        info.position = Some(position);
+                        true
+                    }
+                });
+            }
+        }
+    }
+
+    fn persist(&self, ordinal: i64) {
+        if let Some(job) = self.jobs.get(&ordinal) {
+            PERSIST
+                .send(Persist::Upsert(Box::new(JobRow::new(ordinal, job))))
+                .ok();
+        }
+    }
+
+    /// A job is active until it's attached, for the device's purposes
+    fn is_active(job: &Job) -> bool {
+        matches!(
+            job.info.borrow().status,
+            JobStatus::Queued | JobStatus::Claimed | JobStatus::Launching
+        )
+    }
+
+    fn restore(&mut self, db: &sqlite::Connection, max_attempts: i64) {
+        let query = "SELECT * FROM launch_queue ORDER BY ordinal ASC";
+        let mut statement = match crate::db::db_prepare(db, query) {
             Some(s) => s,
             None => {
                 log::error!("Failed to prepare query!");
-                return LaunchQueueInfo::ServerError;
+                return;
             }
         };
-        statement.bind((1, udid.as_str())).unwrap();
-        let (ordinal, status) = if let Some(State::Row) = crate::db::statement_next(&mut statement)
-        {
+
+        let mut restored = Vec::new();
+        while let Some(State::Row) = crate::db::statement_next(&mut statement) {
             let ordinal = statement.read::<i64, _>("ordinal").unwrap();
             let status = statement.read::<i64, _>("status").unwrap();
-            debug!(
-                "Found device with ordinal {} and status {}",
-                ordinal, status
-            );
-            (ordinal as usize, status as usize)
-        } else {
-            log::debug!("No device found for UDID {:?}", udid);
-            return LaunchQueueInfo::NotInQueue;
-        };
+            // Even a skipped row's ordinal is taken, a new job mustn't overwrite it
+            self.last_ordinal = self.last_ordinal.max(ordinal);
+            let Some(status) = JobStatus::from_i64(status) else {
+                warn!("Skipping launch job {ordinal} with unknown status {status}");
+                continue;
+            };
+            let bundle_id = statement.read::<String, _>("bundle_id").unwrap();
+            let kind = match (
+                statement.read::<i64, _>("kind").unwrap(),
+                statement.read::<Option<i64>, _>("pid").unwrap(),
+            ) {
+                (1, Some(pid)) => JobKind::Attach(AttachTarget::Pid(pid as u64)),
+                (1, None) => JobKind::Attach(AttachTarget::Name(bundle_id.clone())),
+                _ => JobKind::Launch {
+                    bundle_id: bundle_id.clone(),
+                    options: LaunchOptions {
+                        arguments: statement
+                            .read::<Option<String>, _>("arguments")
+                            .unwrap()
+                            .and_then(|a| serde_json::from_str(&a).ok())
+                            .unwrap_or_default(),
+                        environment: statement
+                            .read::<Option<String>, _>("environment")
+                            .unwrap()
+                            .and_then(|e| serde_json::from_str(&e).ok())
+                            .unwrap_or_default(),
+                        kill_existing: statement.read::<i64, _>("kill_existing").unwrap() != 0,
+                        persistent: statement.read::<i64, _>("persistent").unwrap() != 0,
+                        forwarded: statement.read::<i64, _>("forwarded").unwrap() != 0,
+                    },
+                },
+            };
+            let mut info = JobInfo {
+                job_id: ordinal,
+                udid: statement.read::<String, _>("udid").unwrap(),
+                status,
+                position: None,
+                bundle_id: if bundle_id.is_empty() {
+                    None
+                } else {
+                    Some(bundle_id)
+                },
+                pid: statement
+                    .read::<Option<i64>, _>("launched_pid")
+                    .unwrap()
+                    .map(|p| p as u64),
+                error: statement.read::<Option<String>, _>("error").unwrap(),
+                error_code: statement.read::<Option<String>, _>("error_code").unwrap(),
+                attempts: statement.read::<i64, _>("attempts").unwrap(),
+                created_at: statement.read::<Option<String>, _>("created_at").unwrap(),
+                claimed_at: statement.read::<Option<String>, _>("claimed_at").unwrap(),
+                updated_at: statement.read::<Option<String>, _>("updated_at").unwrap(),
+                finished_at: statement.read::<Option<String>, _>("finished_at").unwrap(),
+            };
 
-        match status {
-            1 => return LaunchQueueInfo::Position(0),
-            2 => {
-                let query = "SELECT error FROM launch_queue WHERE ordinal = ?";
-                let mut statement = match crate::db::db_prepare(&db, query) {
-                    Some(s) => s,
-                    None => {
-                        log::error!("Failed to prepare query!");
-                        return LaunchQueueInfo::ServerError;
-                    }
-                };
-                statement.bind((1, ordinal as i64)).unwrap();
-                let error = if let Some(State::Row) = crate::db::statement_next(&mut statement) {
-                    statement.read::<String, _>("error").unwrap()
+            let finished = info.finished_at.is_some() || info.status == JobStatus::Failed;
+            if !finished && info.status != JobStatus::Queued {
+                if info.attempts >= max_attempts {
+                    info.status = JobStatus::Failed;
+                    info.error = Some("The server restarted during the launch".to_string());
+                    info.error_code = Some("lease_expired".to_string());
+                    info.finished_at = Some(timestamp());
                 } else {
-                    "Unknown error".to_string()
-                };
-                // Delete the record from the database
-                let query = "DELETE FROM launch_queue WHERE ordinal = ?";
-                let mut statement = match crate::db::db_prepare(&db, query) {
-                    Some(s) => s,
-                    None => {
-                        log::error!("Failed to prepare query!");
-                        return LaunchQueueInfo::ServerError;
-                    }
-                };
-                statement.bind((1, ordinal as i64)).unwrap();
-                if crate::db::statement_next(&mut statement).is_none() {
-                    log::error!("Failed to delete record");
+                    info.status = JobStatus::Queued;
                 }
-                return LaunchQueueInfo::Error(error);
+                restored.push(ordinal);
             }
-            _ => {}
+
+            self.insert(
+                ordinal,
+                Job {
+                    ip: statement.read::<String, _>("ip").unwrap(),
+                    kind,
+                    acknowledged: statement.read::<i64, _>("acknowledged").unwrap() != 0,
+                    lease_expires: None,
+                    // Restarts count as the start of the retention period
+                    finished: info.finished_at.as_ref().map(|_| Instant::now()),
+                    info: watch::channel(info).0,
+                },
+            );
         }
 
-        // Determine the position of the UDID
-        let query = "SELECT COUNT(*) FROM launch_queue WHERE ordinal < ? AND status = 0";
-        let mut statement = match crate::db::db_prepare(&db, query) {
-            Some(s) => s,
+        for ordinal in restored {
+            self.persist(ordinal);
+        }
+        self.reposition(0);
+        info!(
+            "Restored {} launch jobs, {} queued",
+            self.jobs.len(),
+            self.pending.len()
+        );
+    }
+
+    fn queue_info(&mut self, udid: &str) -> LaunchQueueInfo {
+        let ordinal = match self.by_udid.get(udid).and_then(|jobs| {
+            jobs.iter().copied().find(|o| {
+                let job = &self.jobs[o];
+                Queue::is_active(job)
+                    || (job.info.borrow().status == JobStatus::Failed && !job.acknowledged)
+            })
+        }) {
+            Some(o) => o,
             None => {
-                log::error!("Failed to prepare query!");
-                return LaunchQueueInfo::ServerError;
+                debug!("No job found for UDID {:?}", udid);
+                return LaunchQueueInfo::NotInQueue;
             }
         };
-        statement.bind((1, ordinal as i64)).unwrap();
-        let position = if let Some(State::Row) = crate::db::statement_next(&mut statement) {
-            statement.read::<i64, _>(0).unwrap()
-        } else {
-            return LaunchQueueInfo::ServerError;
+
+        let info = self.jobs[&ordinal].info.borrow().clone();
+        debug!(
+            "Found job {ordinal} with status {:?} for {udid}",
+            info.status
+        );
+        match info.status {
+            JobStatus::Failed => {
+                // Keep the job for /status/{job_id}, but don't report it here again
+                self.jobs.get_mut(&ordinal).unwrap().acknowledged = true;
+                self.persist(ordinal);
+                LaunchQueueInfo::Error(info.error.unwrap_or("Unknown error".to_string()))
+            }
+            _ => LaunchQueueInfo::Position(info.position.unwrap_or(0)),
+        }
+    }
+
+    fn add(&mut self, udid: &str, ip: String, job: &JobKind) -> (i64, usize) {
+        let ordinal = self.last_ordinal + 1;
+        let bundle_id = match job {
+            JobKind::Launch { bundle_id, .. } => Some(bundle_id.clone()),
+            JobKind::Attach(AttachTarget::Name(name)) => Some(name.clone()),
+            JobKind::Attach(AttachTarget::Pid(_)) => None,
         };
+        let now = timestamp();
+        let position = self.pending.len();
+        let info = JobInfo {
+            job_id: ordinal,
+            udid: udid.to_string(),
+            status: JobStatus::Queued,
+            position: Some(position),
+            bundle_id,
+            pid: None,
+            error: None,
+            error_code: None,
+            attempts: 0,
+            created_at: Some(now.clone()),
+            claimed_at: None,
+            updated_at: Some(now),
+            finished_at: None,
+        };
+        self.insert(
+            ordinal,
+            Job {
+                ip,
+                kind: job.clone(),
+                acknowledged: false,
+                lease_expires: None,
+                finished: None,
+                info: watch::channel(info).0,
+            },
+        );
+        self.persist(ordinal);
+        (ordinal, position)
+    }
 
-        LaunchQueueInfo::Position(position as usize)
-    })
-    .await
-    .unwrap()
-}
+    /// Whether the claim is still the one working on the job
+    fn holds(&self, claim: Claim) -> bool {
+        self.jobs.get(&claim.ordinal).is_some_and(|job| {
+            let info = job.info.borrow();
+            info.attempts == claim.attempt
+                && info.status != JobStatus::Queued
+                && info.finished_at.is_none()
+        })
+    }
 
-pub async fn add_to_queue(udid: &str, ip: String, bundle_id: &str) -> Option<i64> {
-    let udid = udid.to_string();
-    let bundle_id = bundle_id.to_string();
-    tokio::task::spawn_blocking(move || {
-        let db = match sqlite::open("jitstreamer.db") {
-            Ok(db) => db,
-            Err(e) => {
-                log::error!("Failed to open database: {:?}", e);
-                return None;
+    fn set_status(&mut self, claim: Claim, status: JobStatus, pid: Option<u64>) {
+        // Too late, the job was cancelled, given up on or requeued for another worker
+        if !self.holds(claim) {
+            debug!(
+                "Ignoring progress of a lost claim on launch job {}",
+                claim.ordinal
+            );
+            return;
+        }
+        self.update(claim.ordinal, |_, info| {
+            info.status = status;
+            if pid.is_some() {
+                info.pid = pid;
             }
-        };
+        });
+    }
 
-        let query = "INSERT INTO launch_queue (udid, ip, bundle_id, status) VALUES (?, ?, ?, 0)";
-        let mut statement = match crate::db::db_prepare(&db, query) {
-            Some(s) => s,
-            None => {
-                log::error!("Failed to prepare query!");
-                return None;
+    /// Claims the oldest pending launch the filter accepts for a worker
+    fn claim(&mut self, filter: impl Fn(&JobKind) -> bool) -> Option<LaunchJob> {
+        let ordinal = *self.pending.iter().find(|o| filter(&self.jobs[o].kind))?;
+        let mut attempt = 0;
+        self.update(ordinal, |job, info| {
+            info.status = JobStatus::Claimed;
+            info.attempts += 1;
+            info.claimed_at = Some(timestamp());
+            job.lease_expires = Some(Instant::now() + LEASE_DURATION);
+            attempt = info.attempts;
+        });
+
+        let job = &self.jobs[&ordinal];
+        let udid = job.info.borrow().udid.clone();
+        Some(LaunchJob {
+            udid,
+            ip: job.ip.clone(),
+            kind: job.kind.clone(),
+            ordinal,
+            attempt,
+        })
+    }
+
+    /// Finishes the job, if the claim is still the latest one
+    fn finish(&mut self, ordinal: i64, attempt: i64, f: impl FnOnce(&mut JobInfo)) {
+        if !self.holds(Claim { ordinal, attempt }) {
+            warn!("Ignoring result of attempt {attempt} of launch job {ordinal}");
+            return;
+        }
+        self.update(ordinal, |job, info| {
+            f(info);
+            info.finished_at = Some(timestamp());
+            job.lease_expires = None;
+            job.finished = Some(Instant::now());
+        });
+    }
+
+    fn renew_lease(&mut self, ordinal: i64, attempt: i64) -> bool {
+        if let Some(job) = self.jobs.get_mut(&ordinal) {
+            let info = job.info.borrow();
+            // A requeued job keeps its attempt count until it's claimed again
+            let claimed = info.status != JobStatus::Queued && info.finished_at.is_none();
+            if info.attempts == attempt && claimed {
+                drop(info);
+                job.lease_expires = Some(Instant::now() + LEASE_DURATION);
+                self.persist(ordinal);
+                return true;
             }
-        };
-        statement.bind((1, udid.as_str())).unwrap();
-        statement.bind((2, ip.as_str())).unwrap();
-        statement.bind((3, bundle_id.as_str())).unwrap();
+        }
+        false
+    }
 
+    /// Returns how many jobs went back in the queue
+    fn requeue_expired(&mut self, now: Instant, max_attempts: i64) -> usize {
+        let expired = self
+            .jobs
+            .iter()
+            .filter(|(_, job)| job.finished.is_none() && job.lease_expires.is_some_and(|l| l < now))
+            .map(|(ordinal, _)| *ordinal)
+            .collect::<Vec<_>>();
 
+        let mut requeued = 0;
+        for ordinal in expired {
+            self.update(ordinal, |job, info| {
+                job.lease_expires = None;
+                if info.attempts >= max_attempts {
+                    warn!("Launch job {ordinal} is out of attempts");
+                    info.status = JobStatus::Failed;
+                    info.error = Some("The launch worker stopped responding".to_string());
+                    info.error_code = Some("lease_expired".to_string());
+                    info.finished_at = Some(timestamp());
+                    job.finished = Some(now);
+                } else {
+                    warn!("Requeueing launch job {ordinal} with an expired lease");
+                    info.status = JobStatus::Queued;
+                    requeued += 1;
+                }
+            });
+        }
+        requeued
+    }
 
-        if crate::db::statement_next(&mut statement).is_none() {
-            log::error!("Failed to insert into launch queue");
-            return None;
+    fn acknowledge_failures(&mut self, udid: &str) {
+        let failed = match self.by_udid.get(udid) {
+            Some(jobs) => jobs
+                .iter()
+                .copied()
+                .filter(|o| {
+                    let job = &self.jobs[o];
+                    !job.acknowledged && job.info.borrow().status == JobStatus::Failed
+                })
+                .collect::<Vec<_>>(),
+            None => return,
+        };
+        for ordinal in failed {
+            self.jobs.get_mut(&ordinal).unwrap().acknowledged = true;
+            self.persist(ordinal);
         }
+    }
 
-        // Get the position of the newly added UDID
-        let query = "SELECT COUNT(*) FROM launch_queue WHERE ordinal < (SELECT ordinal FROM launch_queue WHERE udid = ?) AND status = 0";
-        let mut statement = match crate::db::db_prepare(&db, query) {
-            Some(s) => s,
-            None => {
-                log::error!("Failed to prepare query!");
-                return None;
+    fn cancel_queued(&mut self, ordinal: i64) -> bool {
+        if !self.pending.contains(&ordinal) {
+            return false;
+        }
+        self.update(ordinal, |job, info| {
+            info.status = JobStatus::Cancelled;
+            info.finished_at = Some(timestamp());
+            job.finished = Some(Instant::now());
+        });
+        true
+    }
+
+    fn active_jobs(&self, udid: &str) -> Vec<i64> {
+        match self.by_udid.get(udid) {
+            Some(jobs) => jobs
+                .iter()
+                .copied()
+                .filter(|o| self.jobs[o].finished.is_none())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+// create table launch_queue (
+//   udid varchar(40) not null,
+//   bundle_id varchar(255) not null, -- process name for attach jobs, empty when attaching by PID
+//   status int not null, -- see JobStatus
+//   error varchar(255),
+//   ordinal int primary key,
+//   arguments text, -- JSON array
+//   environment text, -- JSON object
+//   kill_existing int not null default 0,
+//   kind int not null default 0, -- 0: launch, 1: attach
+//   pid int, -- target of an attach job
+//   persistent int not null default 0,
+//   launched_pid int,
+//   error_code varchar(64),
+//   created_at datetime,
+//   claimed_at datetime,
+//   updated_at datetime,
+//   finished_at datetime,
+//   acknowledged int not null default 0, -- failure was reported through /status
+//   lease_expires datetime, -- the claiming worker must renew the claim before this
+//   attempts int not null default 0,
+//   forwarded int not null default 0 -- sent by another server's remote backend
+// );
+
+/// Everything in a launch_queue row
+struct JobRow {
+    ordinal: i64,
+    ip: String,
+    kind: i64,
+    target: String,
+    pid: Option<i64>,
+    options: LaunchOptions,
+    acknowledged: bool,
+    lease_expires: Option<String>,
+    info: JobInfo,
+}
+
+impl JobRow {
+    fn new(ordinal: i64, job: &Job) -> Self {
+        let (kind, target, pid, options) = match &job.kind {
+            JobKind::Launch { bundle_id, options } => (0, bundle_id.clone(), None, options.clone()),
+            JobKind::Attach(AttachTarget::Pid(pid)) => (
+                1,
+                String::new(),
+                Some(*pid as i64),
+                LaunchOptions::default(),
+            ),
+            JobKind::Attach(AttachTarget::Name(name)) => {
+                (1, name.clone(), None, LaunchOptions::default())
             }
         };
-        statement.bind((1, udid.as_str())).unwrap();
-        if let Some(State::Row) = crate::db::statement_next(&mut statement) {
-            Some(statement.read::<i64, _>(0).unwrap())
-        } else {
-            None
+        Self {
+            ordinal,
+            ip: job.ip.clone(),
+            kind,
+            target,
+            pid,
+            options,
+            acknowledged: job.acknowledged,
+            lease_expires: job
+                .lease_expires
+                .map(|l| timestamp_in(l.saturating_duration_since(Instant::now()))),
+            info: job.info.borrow().clone(),
         }
-    })
-    .await
-    .unwrap()
+    }
+}
+
+enum Persist {
+    Upsert(Box<JobRow>),
+    Delete(i64),
 }
 
-pub async fn empty() {
-    tokio::task::spawn_blocking(|| {
-        let db = match sqlite::open("jitstreamer.db") {
+/// Writes queue changes to the database in order, on one connection
+fn persist_thread() -> mpsc::UnboundedSender<Persist> {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<Persist>();
+    std::thread::spawn(move || {
+        // Tests get a throwaway database so they don't leave a jitstreamer.db behind
+        let path = if cfg!(test) {
+            ":memory:"
+        } else {
+            "jitstreamer.db"
+        };
+        let db = match sqlite::open(path) {
             Ok(db) => db,
             Err(e) => {
-                log::error!("Failed to open database: {:?}", e);
+                log::error!(
+                    "Failed to open database, launch queue won't be saved: {:?}",
+                    e
+                );
                 return;
             }
         };
+        if cfg!(test) {
+            db.execute(include_str!("sql/up.sql")).unwrap();
+            crate::db::migrate(&db).unwrap();
+        }
+        while let Some(p) = receiver.blocking_recv() {
+            match p {
+                Persist::Upsert(row) => write_row(&db, &row),
+                Persist::Delete(ordinal) => {
+                    let query = "DELETE FROM launch_queue WHERE ordinal = ?";
+                    let mut statement = match crate::db::db_prepare(&db, query) {
+                        Some(s) => s,
+                        None => {
+                            log::error!("Failed to prepare query!");
+                            continue;
+                        }
+                    };
+                    statement.bind((1, ordinal)).unwrap();
+                    if crate::db::statement_next(&mut statement).is_none() {
+                        log::error!("Failed to delete launch job {ordinal}");
+                    }
+                }
+            }
+        }
+    });
+    sender
+}
+
+fn write_row(db: &sqlite::Connection, row: &JobRow) {
+    let query = "INSERT OR REPLACE INTO launch_queue (ordinal, udid, ip, bundle_id, arguments, environment, kill_existing, persistent, kind, pid, status, error, error_code, launched_pid, created_at, claimed_at, updated_at, finished_at, acknowledged, lease_expires, attempts, forwarded) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+    let mut statement = match crate::db::db_prepare(db, query) {
+        Some(s) => s,
+        None => {
+            log::error!("Failed to prepare query!");
+            return;
+        }
+    };
+    let info = &row.info;
+    let arguments = serde_json::to_string(&row.options.arguments).unwrap();
+    let environment = serde_json::to_string(&row.options.environment).unwrap();
+    statement.bind((1, row.ordinal)).unwrap();
+    statement.bind((2, info.udid.as_str())).unwrap();
+    statement.bind((3, row.ip.as_str())).unwrap();
+    statement.bind((4, row.target.as_str())).unwrap();
+    statement.bind((5, arguments.as_str())).unwrap();
+    statement.bind((6, environment.as_str())).unwrap();
+    statement
+        .bind((7, row.options.kill_existing as i64))
+        .unwrap();
+    statement.bind((8, row.options.persistent as i64)).unwrap();
+    statement.bind((9, row.kind)).unwrap();
+    statement.bind((10, row.pid)).unwrap();
+    statement.bind((11, info.status as i64)).unwrap();
+    statement.bind((12, info.error.as_deref())).unwrap();
+    statement.bind((13, info.error_code.as_deref())).unwrap();
+    statement.bind((14, info.pid.map(|p| p as i64))).unwrap();
+    statement.bind((15, info.created_at.as_deref())).unwrap();
+    statement.bind((16, info.claimed_at.as_deref())).unwrap();
+    statement.bind((17, info.updated_at.as_deref())).unwrap();
+    statement.bind((18, info.finished_at.as_deref())).unwrap();
+    statement.bind((19, row.acknowledged as i64)).unwrap();
+    statement.bind((20, row.lease_expires.as_deref())).unwrap();
+    statement.bind((21, info.attempts)).unwrap();
+    statement.bind((22, row.options.forwarded as i64)).unwrap();
+    if crate::db::statement_next(&mut statement).is_none() {
+        log::error!("Failed to save launch job {}", row.ordinal);
+    }
+}
+
+/// Loads the jobs left in the database by the last run.
+/// Their workers are gone, so claimed jobs go back in the queue if they have attempts left.
+pub fn restore() {
+    let db = match sqlite::open("jitstreamer.db") {
+        Ok(db) => db,
+        Err(e) => {
+            log::error!("Failed to open database: {:?}", e);
+            return;
+        }
+    };
+    QUEUE.lock().unwrap().restore(&db, max_attempts());
+}
+
+/// Gets the status of the device's oldest job it hasn't seen finish
+pub fn get_queue_info(udid: &str) -> LaunchQueueInfo {
+    QUEUE.lock().unwrap().queue_info(udid)
+}
+
+/// Queues the job, returning its ordinal (the job ID) and position
+pub fn add_to_queue(udid: &str, ip: String, job: &JobKind) -> (i64, usize) {
+    let res = QUEUE.lock().unwrap().add(udid, ip, job);
+    changed();
+    JOB_AVAILABLE.notify_one();
+    res
+}
+
+/// Waits for a job and claims it
+pub async fn next_job() -> LaunchJob {
+    loop {
+        if let Some(job) = claim_next(|_| true) {
+            return job;
+        }
+        JOB_AVAILABLE.notified().await;
+    }
+}
+
+/// Waits up to `wait` for a job the filter accepts and claims it.
+/// Used by remote workers, which can't take every kind of job.
+pub async fn next_job_where(
+    filter: impl Fn(&JobKind) -> bool,
+    wait: Duration,
+) -> Option<LaunchJob> {
+    // Local workers take turns on JOB_AVAILABLE, so watch for any change instead of taking a turn
+    let mut changes = subscribe();
+    let deadline = tokio::time::Instant::now() + wait;
+    loop {
+        changes.borrow_and_update();
+        if let Some(job) = claim_next(&filter) {
+            return Some(job);
+        }
+        if tokio::time::timeout_at(deadline, changes.changed())
+            .await
+            .is_err()
+        {
+            return None;
+        }
+    }
+}
+
+fn claim_next(filter: impl Fn(&JobKind) -> bool) -> Option<LaunchJob> {
+    let mut queue = QUEUE.lock().unwrap();
+    let job = queue.claim(filter)?;
+    // Wake another worker if there's more to do
+    if !queue.pending.is_empty() {
+        JOB_AVAILABLE.notify_one();
+    }
+    Some(job)
+}
+
+/// Moves a job along its lifecycle, recording the PID once it's known
+pub fn set_status(claim: Claim, status: JobStatus, pid: Option<u64>) {
+    QUEUE.lock().unwrap().set_status(claim, status, pid);
+}
+
+/// Marks a launch as finished, detached or left attached for a session
+pub fn complete(ordinal: i64, attempt: i64, status: JobStatus, pid: u64) {
+    QUEUE.lock().unwrap().finish(ordinal, attempt, |info| {
+        info.status = status;
+        info.pid = Some(pid);
+    });
+}
+
+/// Marks a launch as failed so the device can read the error
+pub fn fail(ordinal: i64, attempt: i64, error: String, error_code: &str) {
+    QUEUE.lock().unwrap().finish(ordinal, attempt, |info| {
+        info.status = JobStatus::Failed;
+        info.error = Some(error);
+        info.error_code = Some(error_code.to_string());
+    });
+}
+
+/// Marks a claimed job as cancelled once its worker has stopped
+pub fn cancel_claimed(ordinal: i64, attempt: i64) {
+    QUEUE
+        .lock()
+        .unwrap()
+        .finish(ordinal, attempt, |info| info.status = JobStatus::Cancelled);
+}
+
+fn max_attempts() -> i64 {
+    std::env::var("LAUNCH_MAX_ATTEMPTS")
+        .unwrap_or("3".to_string())
+        .parse::<i64>()
+        .unwrap_or(3)
+}
+
+/// Extends the claim on a job, called by the worker while it's still working on it.
+/// Returns false if the claim was lost, because the job was cancelled or given to another worker.
+pub fn renew_lease(ordinal: i64, attempt: i64) -> bool {
+    QUEUE.lock().unwrap().renew_lease(ordinal, attempt)
+}
+
+/// Finds claims whose worker stopped renewing them. Jobs with attempts left
+/// go back to the queue in their old place, the rest fail.
+pub fn requeue_expired() {
+    let requeued = QUEUE
+        .lock()
+        .unwrap()
+        .requeue_expired(Instant::now(), max_attempts());
+    for _ in 0..requeued {
+        JOB_AVAILABLE.notify_one();
+    }
+}
+
+/// Marks the device's earlier failures as seen, so /status reports on the new launch
+pub fn acknowledge_failures(udid: &str) {
+    QUEUE.lock().unwrap().acknowledge_failures(udid);
+}
+
+/// Removes jobs that finished over an hour ago
+pub fn prune() {
+    let mut queue = QUEUE.lock().unwrap();
+    let old = queue
+        .jobs
+        .iter()
+        .filter(|(_, job)| {
+            job.finished
+                .is_some_and(|f| f.elapsed() > FINISHED_RETENTION)
+        })
+        .map(|(ordinal, _)| *ordinal)
+        .collect::<Vec<_>>();
+    for ordinal in old {
+        queue.remove(ordinal);
+    }
+}
 
-        let query = "DELETE FROM launch_queue";
-        let mut statement = db.prepare(query).unwrap();
-        if crate::db::statement_next(&mut statement).is_none() {
-            log::error!("Failed to empty launch queue");
+/// Looks up a job by its ordinal
+pub fn get_job(ordinal: i64) -> Option<JobInfo> {
+    QUEUE
+        .lock()
+        .unwrap()
+        .jobs
+        .get(&ordinal)
+        .map(|j| j.info.borrow().clone())
+}
+
+/// Follows a job's changes, the sender is dropped once the job is pruned
+pub fn watch_job(ordinal: i64) -> Option<watch::Receiver<JobInfo>> {
+    QUEUE
+        .lock()
+        .unwrap()
+        .jobs
+        .get(&ordinal)
+        .map(|j| j.info.subscribe())
+}
+
+/// Cancels the job if no worker has claimed it yet, returning whether it did
+pub fn cancel_queued(ordinal: i64) -> bool {
+    QUEUE.lock().unwrap().cancel_queued(ordinal)
+}
+
+/// Gets the device's jobs that haven't finished yet
+pub fn active_jobs(udid: &str) -> Vec<i64> {
+    QUEUE.lock().unwrap().active_jobs(udid)
+}
+
+pub fn timestamp() -> String {
+    timestamp_in(Duration::ZERO)
+}
+
+/// Formats a UTC time like SQLite's CURRENT_TIMESTAMP
+fn timestamp_in(offset: Duration) -> String {
+    let secs = (SystemTime::now() + offset)
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // Days since the epoch to a civil date, from http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn launch(bundle_id: &str) -> JobKind {
+        JobKind::Launch {
+            bundle_id: bundle_id.to_string(),
+            options: LaunchOptions::default(),
         }
-    })
-    .await
-    .unwrap();
+    }
+
+    fn status(queue: &Queue, ordinal: i64) -> JobInfo {
+        queue.jobs[&ordinal].info.borrow().clone()
+    }
+
+    #[test]
+    fn round_trips_statuses() {
+        for s in [
+            JobStatus::Queued,
+            JobStatus::Claimed,
+            JobStatus::Failed,
+            JobStatus::Launching,
+            JobStatus::Attached,
+            JobStatus::Detached,
+            JobStatus::Cancelled,
+        ] {
+            assert_eq!(JobStatus::from_i64(s as i64), Some(s));
+        }
+        assert_eq!(JobStatus::from_i64(7), None);
+        assert_eq!(JobStatus::from_i64(-1), None);
+    }
+
+    #[test]
+    fn claims_in_order_and_repositions() {
+        let mut queue = Queue::default();
+        let (attach, _) = queue.add("a", "ip".into(), &JobKind::Attach(AttachTarget::Pid(1)));
+        let (first, _) = queue.add("a", "ip".into(), &launch("first"));
+        let (second, position) = queue.add("b", "ip".into(), &launch("second"));
+        assert_eq!(position, 2);
+
+        // Skips what the filter turns down
+        let job = queue
+            .claim(|k| matches!(k, JobKind::Launch { .. }))
+            .unwrap();
+        assert_eq!((job.ordinal, job.attempt), (first, 1));
+        let info = status(&queue, first);
+        assert_eq!(info.status, JobStatus::Claimed);
+        assert_eq!(info.position, None);
+        assert!(queue.jobs[&first].lease_expires.is_some());
+
+        assert_eq!(status(&queue, attach).position, Some(0));
+        assert_eq!(status(&queue, second).position, Some(1));
+        // Progress of a claimed job leaves the queued ones alone
+        let watcher = queue.jobs[&second].info.subscribe();
+        queue.set_status(job.claim(), JobStatus::Launching, None);
+        assert!(!watcher.has_changed().unwrap());
+        assert_eq!(queue.active_jobs("a"), vec![attach, first]);
+
+        assert_eq!(queue.claim(|_| true).unwrap().ordinal, attach);
+        assert_eq!(status(&queue, second).position, Some(0));
+        assert_eq!(queue.claim(|_| true).unwrap().ordinal, second);
+        assert!(queue.claim(|_| true).is_none());
+    }
+
+    #[test]
+    fn requeues_expired_claims() {
+        let mut queue = Queue::default();
+        let (ordinal, _) = queue.add("a", "ip".into(), &launch("app"));
+        let later = Instant::now() + LEASE_DURATION * 2;
+
+        let job = queue.claim(|_| true).unwrap();
+        assert_eq!(queue.requeue_expired(Instant::now(), 2), 0);
+        assert_eq!(queue.requeue_expired(later, 2), 1);
+        let info = status(&queue, ordinal);
+        assert_eq!(info.status, JobStatus::Queued);
+        assert_eq!(info.position, Some(0));
+        // The old claim can't renew the job or move it along any more
+        assert!(!queue.renew_lease(ordinal, job.attempt));
+        queue.set_status(job.claim(), JobStatus::Launching, None);
+        assert_eq!(status(&queue, ordinal).status, JobStatus::Queued);
+        assert!(queue.pending.contains(&ordinal));
+        // Nor does finishing it touch the job at all
+        let watcher = queue.jobs[&ordinal].info.subscribe();
+        queue.finish(ordinal, job.attempt, |info| info.status = JobStatus::Failed);
+        assert!(!watcher.has_changed().unwrap());
+
+        let job = queue.claim(|_| true).unwrap();
+        assert_eq!(job.attempt, 2);
+        assert!(queue.renew_lease(ordinal, job.attempt));
+        assert_eq!(queue.requeue_expired(later, 2), 0);
+        let info = status(&queue, ordinal);
+        assert_eq!(info.status, JobStatus::Failed);
+        assert_eq!(info.error_code.as_deref(), Some("lease_expired"));
+        assert!(info.finished_at.is_some());
+        assert!(queue.active_jobs("a").is_empty());
+    }
+
+    #[test]
+    fn cancels_only_queued_jobs() {
+        let mut queue = Queue::default();
+        let (claimed, _) = queue.add("a", "ip".into(), &launch("first"));
+        let (queued, _) = queue.add("a", "ip".into(), &launch("second"));
+        let job = queue.claim(|_| true).unwrap();
+
+        assert!(!queue.cancel_queued(claimed));
+        assert!(queue.cancel_queued(queued));
+        assert!(!queue.cancel_queued(queued));
+        assert_eq!(status(&queue, queued).status, JobStatus::Cancelled);
+        assert_eq!(queue.active_jobs("a"), vec![claimed]);
+
+        queue.finish(claimed, job.attempt, |info| {
+            info.status = JobStatus::Cancelled
+        });
+        assert!(queue.active_jobs("a").is_empty());
+    }
+
+    #[test]
+    fn reports_failures_until_acknowledged() {
+        let mut queue = Queue::default();
+        for _ in 0..2 {
+            queue.add("a", "ip".into(), &launch("app"));
+            let job = queue.claim(|_| true).unwrap();
+            queue.finish(job.ordinal, job.attempt, |info| {
+                info.status = JobStatus::Failed;
+                info.error = Some("broke".to_string());
+            });
+        }
+
+        assert!(matches!(queue.queue_info("a"), LaunchQueueInfo::Error(e) if e == "broke"));
+        queue.acknowledge_failures("a");
+        assert!(matches!(queue.queue_info("a"), LaunchQueueInfo::NotInQueue));
+
+        queue.add("a", "ip".into(), &launch("app"));
+        assert!(matches!(
+            queue.queue_info("a"),
+            LaunchQueueInfo::Position(0)
+        ));
+    }
+
+    #[test]
+    fn restores_jobs() {
+        let db = sqlite::open(":memory:").unwrap();
+        db.execute(include_str!("sql/up.sql")).unwrap();
+        crate::db::migrate(&db).unwrap();
+
+        let mut queue = Queue::default();
+        let (queued, _) = queue.add("a", "ip".into(), &launch("queued"));
+        let (retry, _) = queue.add("a", "ip".into(), &JobKind::Attach(AttachTarget::Pid(42)));
+        let (out_of_attempts, _) = queue.add("b", "ip".into(), &launch("out"));
+        let (done, _) = queue.add("c", "ip".into(), &launch("done"));
+        let (unknown, _) = queue.add("d", "ip".into(), &launch("unknown"));
+
+        queue.update(retry, |_, info| {
+            info.status = JobStatus::Launching;
+            info.attempts = 1;
+        });
+        queue.update(out_of_attempts, |_, info| {
+            info.status = JobStatus::Claimed;
+            info.attempts = 2;
+        });
+        queue.update(done, |_, info| {
+            info.status = JobStatus::Detached;
+            info.attempts = 1;
+            info.pid = Some(7);
+            info.finished_at = Some(timestamp());
+        });
+        for ordinal in [queued, retry, out_of_attempts, done, unknown] {
+            write_row(&db, &JobRow::new(ordinal, &queue.jobs[&ordinal]));
+        }
+        db.execute(format!(
+            "UPDATE launch_queue SET status = 99 WHERE ordinal = {unknown}"
+        ))
+        .unwrap();
+
+        let mut restored = Queue::default();
+        restored.restore(&db, 2);
+        assert!(!restored.jobs.contains_key(&unknown));
+        assert_eq!(
+            restored.pending.iter().copied().collect::<Vec<_>>(),
+            vec![queued, retry]
+        );
+
+        let info = status(&restored, retry);
+        assert_eq!((info.status, info.position), (JobStatus::Queued, Some(1)));
+        assert!(matches!(
+            restored.jobs[&retry].kind,
+            JobKind::Attach(AttachTarget::Pid(42))
+        ));
+        let info = status(&restored, out_of_attempts);
+        assert_eq!(info.status, JobStatus::Failed);
+        assert_eq!(info.error_code.as_deref(), Some("lease_expired"));
+        let info = status(&restored, done);
+        assert_eq!((info.status, info.pid), (JobStatus::Detached, Some(7)));
+        assert!(restored.jobs[&done].finished.is_some());
+        assert_eq!(restored.last_ordinal, unknown);
+        assert_eq!(
+            restored.add("e", "ip".into(), &launch("new")).0,
+            unknown + 1
+        );
+    }
 }
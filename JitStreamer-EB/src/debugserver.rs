@@ -0,0 +1,180 @@
+// Jackson Coxson
+// Client for the debugserver exposed through debugproxy
+
+use log::{debug, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    gdb::{self, Frame, StopReply},
+    launch::LaunchError,
+};
+
+pub const SERVICE_NAME: &str = "com.apple.internal.dt.remote.debugproxy";
+
+pub struct DebugserverClient<S> {
+    stream: S,
+    decoder: gdb::Decoder,
+    ack_mode: bool,
+    last_sent: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> DebugserverClient<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            decoder: gdb::Decoder::default(),
+            ack_mode: true,
+            last_sent: Vec::new(),
+        }
+    }
+
+    pub async fn send_packet(&mut self, payload: &str) -> Result<(), LaunchError> {
+        debug!("debugserver <- {payload}");
+        self.last_sent = gdb::encode_packet(payload.as_bytes());
+        self.stream.write_all(&self.last_sent).await?;
+        Ok(())
+    }
+
+    /// Reads the next packet, handling acks along the way
+    pub async fn read_packet(&mut self) -> Result<String, LaunchError> {
+        let mut buf = [0u8; 1024];
+        loop {
+            while let Some(frame) = self.decoder.next_frame() {
+                match frame {
+                    Ok(Frame::Packet(payload)) => {
+                        if self.ack_mode {
+                            self.stream.write_all(b"+").await?;
+                        }
+                        let payload = String::from_utf8_lossy(&payload).to_string();
+                        debug!("debugserver -> {payload}");
+                        return Ok(payload);
+                    }
+                    Ok(Frame::Nack) => {
+                        debug!("debugserver requested a retransmit");
+                        self.stream.write_all(&self.last_sent).await?;
+                    }
+                    Ok(Frame::Ack) => {}
+                    Ok(Frame::Notification(n)) => {
+                        debug!("debugserver notification: {}", String::from_utf8_lossy(&n));
+                    }
+                    Err(e) => {
+                        warn!("Bad packet from debugserver: {e}");
+                        if self.ack_mode {
+                            self.stream.write_all(b"-").await?;
+                        }
+                    }
+                }
+            }
+
+            let n = self.stream.read(&mut buf).await?;
+            if n == 0 {
+                return Err(LaunchError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.decoder.feed(&buf[..n]);
+        }
+    }
+
+    pub async fn command(&mut self, payload: &str) -> Result<String, LaunchError> {
+        self.send_packet(payload).await?;
+        self.read_packet().await
+    }
+
+    pub async fn start_no_ack_mode(&mut self) -> Result<(), LaunchError> {
+        let res = self.command("QStartNoAckMode").await?;
+        if StopReply::parse(&res) != StopReply::Ok {
+            return Err(LaunchError::Debugserver(res));
+        }
+        self.ack_mode = false;
+        self.decoder.set_verify_checksums(false);
+        Ok(())
+    }
+
+    pub async fn set_detach_on_error(&mut self) -> Result<(), LaunchError> {
+        let res = self.command("QSetDetachOnError:1").await?;
+        if StopReply::parse(&res) != StopReply::Ok {
+            return Err(LaunchError::Debugserver(res));
+        }
+        Ok(())
+    }
+
+    /// Attaches to the process, returning the stop reply
+    pub async fn attach(&mut self, pid: u64) -> Result<StopReply, LaunchError> {
+        let res = self.command(&format!("vAttach;{pid:x}")).await?;
+        match StopReply::parse(&res) {
+            r @ StopReply::Signal { .. } => Ok(r),
+            _ => Err(LaunchError::AttachFailed(res)),
+        }
+    }
+
+    /// Resumes the process, optionally delivering a signal, and waits for it to stop again.
+    /// This blocks for as long as the process keeps running.
+    pub async fn resume(&mut self, signal: Option<u8>) -> Result<StopReply, LaunchError> {
+        let res = match signal {
+            Some(s) => self.command(&format!("C{s:02x}")).await?,
+            None => self.command("c").await?,
+        };
+        Ok(StopReply::parse(&res))
+    }
+
+    pub async fn read_register(&mut self, thread: u64, register: u32) -> Result<u64, LaunchError> {
+        let res = self
+            .command(&format!("p{register:x};thread:{thread:x};"))
+            .await?;
+        let bytes = decode_hex(&res).ok_or_else(|| LaunchError::Debugserver(res.clone()))?;
+        let mut value = [0u8; 8];
+        let len = bytes.len().min(8);
+        value[..len].copy_from_slice(&bytes[..len]);
+        Ok(u64::from_le_bytes(value))
+    }
+
+    pub async fn write_register(
+        &mut self,
+        thread: u64,
+        register: u32,
+        value: u64,
+    ) -> Result<(), LaunchError> {
+        let res = self
+            .command(&format!(
+                "P{register:x}={};thread:{thread:x};",
+                encode_hex(&value.to_le_bytes())
+            ))
+            .await?;
+        if StopReply::parse(&res) != StopReply::Ok {
+            return Err(LaunchError::Debugserver(res));
+        }
+        Ok(())
+    }
+
+    pub async fn read_memory(&mut self, address: u64, len: usize) -> Result<Vec<u8>, LaunchError> {
+        let res = self.command(&format!("m{address:x},{len:x}")).await?;
+        decode_hex(&res).ok_or(LaunchError::Debugserver(res))
+    }
+
+    /// Allocates memory in the process, permissions are some of "rwx"
+    pub async fn allocate(&mut self, size: u64, permissions: &str) -> Result<u64, LaunchError> {
+        let res = self.command(&format!("_M{size:x},{permissions}")).await?;
+        u64::from_str_radix(&res, 16).map_err(|_| LaunchError::Debugserver(res))
+    }
+
+    pub async fn detach(&mut self) -> Result<(), LaunchError> {
+        let res = self.command("D").await?;
+        if StopReply::parse(&res) != StopReply::Ok {
+            return Err(LaunchError::DetachFailed(res));
+        }
+        Ok(())
+    }
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
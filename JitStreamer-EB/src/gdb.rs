@@ -0,0 +1,384 @@
+// Jackson Coxson
+// Framing and parsing for the GDB remote serial protocol spoken by debugserver.
+// https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+
+/// A single unit read off the wire
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    Ack,
+    Nack,
+    /// `$...#xx`, unescaped and run-length expanded
+    Packet(Vec<u8>),
+    /// `%...#xx` asynchronous notification
+    Notification(Vec<u8>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GdbError {
+    ChecksumMismatch { expected: u8, actual: u8 },
+    InvalidChecksum,
+    InvalidRunLength,
+    TrailingEscape,
+}
+
+impl std::fmt::Display for GdbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GdbError::ChecksumMismatch { expected, actual } => {
+                write!(f, "bad checksum, expected {expected:02x} got {actual:02x}")
+            }
+            GdbError::InvalidChecksum => write!(f, "checksum is not hex"),
+            GdbError::InvalidRunLength => write!(f, "invalid run-length encoding"),
+            GdbError::TrailingEscape => write!(f, "packet ends in an escape"),
+        }
+    }
+}
+
+/// Modulo 256 sum of the bytes as they appear on the wire
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+}
+
+/// Escapes the bytes that can't appear in a packet body
+pub fn escape(payload: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(payload.len());
+    for b in payload {
+        match b {
+            b'$' | b'#' | b'}' | b'*' => {
+                res.push(b'}');
+                res.push(b ^ 0x20);
+            }
+            _ => res.push(*b),
+        }
+    }
+    res
+}
+
+/// Frames a payload as `$<escaped payload>#<checksum>`
+pub fn encode_packet(payload: &[u8]) -> Vec<u8> {
+    let body = escape(payload);
+    let mut res = Vec::with_capacity(body.len() + 4);
+    res.push(b'$');
+    res.extend_from_slice(&body);
+    res.push(b'#');
+    res.extend_from_slice(format!("{:02x}", checksum(&body)).as_bytes());
+    res
+}
+
+/// Undoes escaping and run-length encoding of a packet body
+pub fn decode_body(body: &[u8]) -> Result<Vec<u8>, GdbError> {
+    let mut res = Vec::with_capacity(body.len());
+    let mut iter = body.iter();
+    while let Some(b) = iter.next() {
+        match b {
+            b'}' => match iter.next() {
+                Some(e) => res.push(e ^ 0x20),
+                None => return Err(GdbError::TrailingEscape),
+            },
+            b'*' => {
+                let count = match iter.next() {
+                    Some(c) if (b' '..=b'~').contains(c) && *c != b'$' && *c != b'#' => c - 29,
+                    _ => return Err(GdbError::InvalidRunLength),
+                };
+                let last = match res.last() {
+                    Some(l) => *l,
+                    None => return Err(GdbError::InvalidRunLength),
+                };
+                res.resize(res.len() + count as usize, last);
+            }
+            _ => res.push(*b),
+        }
+    }
+    Ok(res)
+}
+
+/// Splits a byte stream into frames.
+/// Bytes are fed in as they arrive and complete frames are pulled out.
+pub struct Decoder {
+    buf: Vec<u8>,
+    verify_checksums: bool,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self {
+            buf: Vec::new(),
+            verify_checksums: true,
+        }
+    }
+}
+
+impl Decoder {
+    /// Checksums are meaningless once QStartNoAckMode has been accepted
+    pub fn set_verify_checksums(&mut self, verify: bool) {
+        self.verify_checksums = verify;
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Returns the next complete frame, or None if more bytes are needed
+    pub fn next_frame(&mut self) -> Option<Result<Frame, GdbError>> {
+        loop {
+            let first = *self.buf.first()?;
+            match first {
+                b'+' => {
+                    self.buf.remove(0);
+                    return Some(Ok(Frame::Ack));
+                }
+                b'-' => {
+                    self.buf.remove(0);
+                    return Some(Ok(Frame::Nack));
+                }
+                b'$' | b'%' => {
+                    let end = self.buf.iter().position(|b| *b == b'#')?;
+                    if self.buf.len() < end + 3 {
+                        return None;
+                    }
+                    let frame: Vec<u8> = self.buf.drain(..end + 3).collect();
+                    let body = &frame[1..end];
+
+                    if self.verify_checksums {
+                        let actual = match std::str::from_utf8(&frame[end + 1..])
+                            .ok()
+                            .and_then(|c| u8::from_str_radix(c, 16).ok())
+                        {
+                            Some(c) => c,
+                            None => return Some(Err(GdbError::InvalidChecksum)),
+                        };
+                        let expected = checksum(body);
+                        if expected != actual {
+                            return Some(Err(GdbError::ChecksumMismatch { expected, actual }));
+                        }
+                    }
+
+                    return Some(decode_body(body).map(|b| {
+                        if first == b'$' {
+                            Frame::Packet(b)
+                        } else {
+                            Frame::Notification(b)
+                        }
+                    }));
+                }
+                _ => {
+                    // Line noise, skip it
+                    self.buf.remove(0);
+                }
+            }
+        }
+    }
+}
+
+/// Replies to vAttach, vCont, ? and friends, as well as the plain OK/Exx replies
+#[derive(Debug, PartialEq)]
+pub enum StopReply {
+    Ok,
+    Error(u8),
+    /// T and S packets. Fields are the `name:value;` pairs in order.
+    Signal {
+        signal: u8,
+        thread: Option<u64>,
+        fields: Vec<(String, String)>,
+    },
+    Exited(u8),
+    Terminated(u8),
+    Other(String),
+}
+
+impl StopReply {
+    pub fn parse(payload: &str) -> Self {
+        if payload == "OK" {
+            return StopReply::Ok;
+        }
+        let (kind, rest) = match payload.chars().next() {
+            Some(k) => (k, &payload[k.len_utf8()..]),
+            None => return StopReply::Other(String::new()),
+        };
+        let code = rest.get(..2).and_then(|c| u8::from_str_radix(c, 16).ok());
+        match (kind, code) {
+            ('E', Some(c)) => StopReply::Error(c),
+            ('W', Some(c)) => StopReply::Exited(c),
+            ('X', Some(c)) => StopReply::Terminated(c),
+            ('T' | 'S', Some(signal)) => {
+                let fields: Vec<(String, String)> = rest[2..]
+                    .split(';')
+                    .filter_map(|f| f.split_once(':'))
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect();
+                let thread = fields
+                    .iter()
+                    .find(|(k, _)| k == "thread")
+                    .and_then(|(_, v)| u64::from_str_radix(v, 16).ok());
+                StopReply::Signal {
+                    signal,
+                    thread,
+                    fields,
+                }
+            }
+            _ => StopReply::Other(payload.to_string()),
+        }
+    }
+
+    /// Looks up a `name:value` field of a T packet
+    pub fn field(&self, name: &str) -> Option<&str> {
+        match self {
+            StopReply::Signal { fields, .. } => fields
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(wire: &[u8]) -> Vec<Result<Frame, GdbError>> {
+        let mut decoder = Decoder::default();
+        decoder.feed(wire);
+        std::iter::from_fn(|| decoder.next_frame()).collect()
+    }
+
+    #[test]
+    fn encodes_launch_py_packets() {
+        // The packets launch.py used to send by hand
+        assert_eq!(encode_packet(b"QStartNoAckMode"), b"$QStartNoAckMode#b0");
+        assert_eq!(
+            encode_packet(b"QSetDetachOnError:1"),
+            b"$QSetDetachOnError:1#f8"
+        );
+        assert_eq!(encode_packet(b"D"), b"$D#44");
+    }
+
+    #[test]
+    fn checksum_depends_on_pid() {
+        assert_eq!(encode_packet(b"vAttach;1a2b"), b"$vAttach;1a2b#2c");
+        assert_eq!(encode_packet(b"vAttach;3e8"), b"$vAttach;3e8#d6");
+    }
+
+    #[test]
+    fn escapes_reserved_bytes() {
+        assert_eq!(encode_packet(b"a#b"), b"$a}\x03b#43");
+        assert_eq!(escape(b"$}*"), b"}\x04}]}\x0a");
+        assert_eq!(decode_body(&escape(b"$#}*")).unwrap(), b"$#}*");
+    }
+
+    #[test]
+    fn attach_transcript() {
+        // QStartNoAckMode is acked before no-ack mode takes effect
+        let frames = decode_all(b"+$OK#9a");
+        assert_eq!(
+            frames,
+            vec![Ok(Frame::Ack), Ok(Frame::Packet(b"OK".to_vec()))]
+        );
+
+        let wire = b"$T11thread:3a1f;00:0*,;name:Dolphin;threads:3a1f,3a20;reason:signal;#35";
+        let frames = decode_all(wire);
+        let payload = match &frames[..] {
+            [Ok(Frame::Packet(p))] => String::from_utf8(p.clone()).unwrap(),
+            _ => panic!("unexpected frames {frames:?}"),
+        };
+        let reply = StopReply::parse(&payload);
+        match &reply {
+            StopReply::Signal { signal, thread, .. } => {
+                assert_eq!(*signal, 0x11);
+                assert_eq!(*thread, Some(0x3a1f));
+            }
+            _ => panic!("unexpected reply {reply:?}"),
+        }
+        assert_eq!(reply.field("00"), Some("0000000000000000"));
+        assert_eq!(reply.field("name"), Some("Dolphin"));
+        assert_eq!(reply.field("reason"), Some("signal"));
+
+        let frames = decode_all(b"$OK#9a");
+        assert_eq!(frames, vec![Ok(Frame::Packet(b"OK".to_vec()))]);
+    }
+
+    #[test]
+    fn failed_attach_transcript() {
+        let frames = decode_all(b"$E08#ad");
+        match &frames[..] {
+            [Ok(Frame::Packet(p))] => {
+                assert_eq!(
+                    StopReply::parse(std::str::from_utf8(p).unwrap()),
+                    StopReply::Error(8)
+                )
+            }
+            _ => panic!("unexpected frames {frames:?}"),
+        }
+    }
+
+    #[test]
+    fn split_reads() {
+        let wire = b"$T05thread:1c;00:0*,;reason:breakpoint;#c1";
+        let mut decoder = Decoder::default();
+        for chunk in wire.chunks(7) {
+            assert_eq!(decoder.next_frame(), None);
+            decoder.feed(chunk);
+        }
+        let frame = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(
+            frame,
+            Frame::Packet(b"T05thread:1c;00:0000000000000000;reason:breakpoint;".to_vec())
+        );
+        assert_eq!(decoder.next_frame(), None);
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        // The fixed checksum launch.py used for every vAttach
+        let frames = decode_all(b"$vAttach;1a2b#38");
+        assert_eq!(
+            frames,
+            vec![Err(GdbError::ChecksumMismatch {
+                expected: 0x2c,
+                actual: 0x38
+            })]
+        );
+
+        let mut decoder = Decoder::default();
+        decoder.set_verify_checksums(false);
+        decoder.feed(b"$OK#00");
+        assert_eq!(
+            decoder.next_frame(),
+            Some(Ok(Frame::Packet(b"OK".to_vec())))
+        );
+    }
+
+    #[test]
+    fn run_length() {
+        assert_eq!(decode_body(b"0*\"").unwrap(), b"000000");
+        assert_eq!(decode_body(b"*\""), Err(GdbError::InvalidRunLength));
+        assert_eq!(decode_body(b"0*\x01"), Err(GdbError::InvalidRunLength));
+    }
+
+    #[test]
+    fn skips_noise_and_notifications() {
+        let frames = decode_all(b"\r\n-%Stop:T05#99");
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], Ok(Frame::Nack));
+        assert!(matches!(frames[1], Ok(Frame::Notification(_))));
+    }
+
+    #[test]
+    fn stop_replies() {
+        assert_eq!(StopReply::parse("OK"), StopReply::Ok);
+        assert_eq!(StopReply::parse("W00"), StopReply::Exited(0));
+        assert_eq!(StopReply::parse("X09"), StopReply::Terminated(9));
+        assert_eq!(
+            StopReply::parse("S05"),
+            StopReply::Signal {
+                signal: 5,
+                thread: None,
+                fields: Vec::new()
+            }
+        );
+        assert_eq!(
+            StopReply::parse("qSupported"),
+            StopReply::Other("qSupported".to_string())
+        );
+    }
+}
@@ -10,25 +10,36 @@ use idevice::{
 use log::debug;
 use tokio::sync::oneshot::error::TryRecvError;
 
+/// What a heartbeat is for. Heartbeats are kept per device and owner, so one owner
+/// finishing doesn't stop the heartbeat another is still relying on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Owner {
+    Apps,
+    /// A debug session, by the PID it's attached to
+    Session(u64),
+    /// An lldb bridge, by its ID
+    Bridge(u64),
+}
+
 pub enum SendRequest {
-    Store((String, tokio::sync::oneshot::Sender<()>)),
-    Kill(String),
+    Store((String, Owner, tokio::sync::oneshot::Sender<()>)),
+    Kill((String, Owner)),
 }
 pub type NewHeartbeatSender = tokio::sync::mpsc::Sender<SendRequest>;
 
 pub fn heartbeat() -> NewHeartbeatSender {
     let (sender, mut receiver) = tokio::sync::mpsc::channel::<SendRequest>(100);
     tokio::task::spawn(async move {
-        let mut cache: HashMap<String, tokio::sync::oneshot::Sender<()>> = HashMap::new();
+        let mut cache: HashMap<(String, Owner), tokio::sync::oneshot::Sender<()>> = HashMap::new();
         while let Some(msg) = receiver.recv().await {
             match msg {
-                SendRequest::Store((udid, handle)) => {
-                    if let Some(old_sender) = cache.insert(udid, handle) {
+                SendRequest::Store((udid, owner, handle)) => {
+                    if let Some(old_sender) = cache.insert((udid, owner), handle) {
                         old_sender.send(()).ok();
                     }
                 }
-                SendRequest::Kill(udid) => {
-                    if let Some(old_sender) = cache.remove(&udid) {
+                SendRequest::Kill(key) => {
+                    if let Some(old_sender) = cache.remove(&key) {
                         old_sender.send(()).ok();
                     }
                 }
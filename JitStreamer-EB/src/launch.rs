@@ -0,0 +1,622 @@
+// Jackson Coxson
+// Launches an app suspended and enables JIT by attaching and detaching debugserver
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::IpAddr,
+    sync::{LazyLock, Mutex, OnceLock},
+    time::Duration,
+};
+
+use idevice::{
+    dvt::{message::AuxValue, remote_server::RemoteServerClient},
+    xpc::XPCDevice,
+    IdeviceError,
+};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+
+use crate::{
+    debug_server::{JobStatus, LaunchOptions, Progress},
+    debugserver::{self, DebugserverClient},
+    gdb::StopReply,
+    netmuxd,
+    tunneld::{Tunnel, TunneldClient, TunneldError},
+    tunnels,
+};
+
+const DVT_SERVICE_NAME: &str = "com.apple.instruments.dtservicehub";
+const DEVICE_INFO_CHANNEL: &str = "com.apple.instruments.server.services.deviceinfo";
+const PROCESS_CONTROL_CHANNEL: &str = "com.apple.instruments.server.services.processcontrol";
+const LAUNCH_METHOD: &str =
+    "launchSuspendedProcessWithDevicePath:bundleIdentifier:environment:arguments:options:";
+/// Apps installed by the user live under here, everything else is system
+const USER_APP_PATH: &str = "/var/containers/Bundle/Application/";
+pub const LAUNCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Set from TUNNEL_MODE at startup
+static TUNNEL_MODE: OnceLock<TunnelMode> = OnceLock::new();
+
+/// How many launches, sessions and bridges need each device tracked by netmuxd
+static NETMUXD_HOLDS: LazyLock<Mutex<HashMap<String, usize>>> = LazyLock::new(Default::default);
+
+/// How the server reaches devices' RSD services
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TunnelMode {
+    /// Through our own tunnels, see tunnels.rs
+    #[default]
+    Builtin,
+    /// Through netmuxd and tunneld running alongside the server
+    Tunneld,
+}
+
+impl TunnelMode {
+    pub fn from_env() -> Result<Self, String> {
+        match std::env::var("TUNNEL_MODE").as_deref() {
+            Ok("builtin") | Err(_) => Ok(TunnelMode::Builtin),
+            Ok("tunneld") => Ok(TunnelMode::Tunneld),
+            Ok(other) => Err(format!(
+                "Unknown TUNNEL_MODE {other}, expected builtin or tunneld"
+            )),
+        }
+    }
+}
+
+/// Picks the tunnel mode for the rest of the run, before any device is reached
+pub fn set_tunnel_mode(mode: TunnelMode) {
+    TUNNEL_MODE.set(mode).ok();
+}
+
+pub fn tunnel_mode() -> TunnelMode {
+    TUNNEL_MODE.get().copied().unwrap_or_default()
+}
+
+#[derive(Debug)]
+pub enum LaunchError {
+    Netmuxd,
+    /// The built in tunnel couldn't be opened
+    Tunnel(String),
+    /// tunneld isn't running or answered with garbage
+    Tunneld(TunneldError),
+    TunnelNotFound,
+    ServiceNotFound(&'static str),
+    Idevice(IdeviceError),
+    Io(std::io::Error),
+    Debugserver(String),
+    AttachFailed(String),
+    DetachFailed(String),
+    ProcessNotFound(String),
+    Timeout,
+    /// The Python launcher failed, see backend.rs
+    Python(String),
+    /// The Python launcher didn't start or died without saying why
+    PythonCrashed(String),
+    /// The remote server failed or couldn't be reached
+    Remote(String),
+    /// The job's device address isn't an IP address
+    BadAddress(String),
+}
+
+impl std::fmt::Display for LaunchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LaunchError::Netmuxd => write!(f, "failed to add device to netmuxd"),
+            LaunchError::Tunnel(e) => write!(f, "failed to open tunnel: {e}"),
+            LaunchError::Tunneld(e) => write!(f, "{e}"),
+            LaunchError::TunnelNotFound => write!(f, "tunneld has no tunnel for the device"),
+            LaunchError::ServiceNotFound(s) => write!(f, "device does not advertise {s}"),
+            LaunchError::Idevice(e) => write!(f, "device error: {e}"),
+            LaunchError::Io(e) => write!(f, "connection error: {e}"),
+            LaunchError::Debugserver(r) => write!(f, "unexpected debugserver response: {r}"),
+            LaunchError::AttachFailed(r) => write!(f, "failed to attach to process: {r}"),
+            LaunchError::DetachFailed(r) => write!(f, "failed to detach from process: {r}"),
+            LaunchError::ProcessNotFound(n) => write!(f, "no running process named {n}"),
+            LaunchError::Timeout => write!(f, "timed out"),
+            LaunchError::Python(e) => write!(f, "python launcher failed: {e}"),
+            LaunchError::PythonCrashed(e) => write!(f, "python launcher crashed: {e}"),
+            LaunchError::Remote(e) => write!(f, "remote launch failed: {e}"),
+            LaunchError::BadAddress(ip) => write!(f, "bad device address {ip}"),
+        }
+    }
+}
+
+impl LaunchError {
+    /// Whether the worker should back off before its next job
+    pub fn is_crash(&self) -> bool {
+        matches!(self, LaunchError::PythonCrashed(_))
+    }
+
+    /// Stable name for the error, for clients to act on
+    pub fn code(&self) -> &'static str {
+        match self {
+            LaunchError::Netmuxd => "netmuxd",
+            LaunchError::Tunnel(_) => "tunnel_failed",
+            LaunchError::Tunneld(TunneldError::Unreachable(_)) => "tunneld_unavailable",
+            LaunchError::Tunneld(TunneldError::BadResponse(_)) => "tunneld_error",
+            LaunchError::TunnelNotFound => "tunnel_not_found",
+            LaunchError::ServiceNotFound(_) => "service_not_found",
+            LaunchError::Idevice(_) => "device_error",
+            LaunchError::Io(_) => "connection_error",
+            LaunchError::Debugserver(_) => "debugserver_error",
+            LaunchError::AttachFailed(_) => "attach_failed",
+            LaunchError::DetachFailed(_) => "detach_failed",
+            LaunchError::ProcessNotFound(_) => "process_not_found",
+            LaunchError::Timeout => "timeout",
+            LaunchError::Python(_) => "python_error",
+            LaunchError::PythonCrashed(_) => "python_crashed",
+            LaunchError::Remote(_) => "remote_error",
+            LaunchError::BadAddress(_) => "bad_address",
+        }
+    }
+}
+
+impl From<IdeviceError> for LaunchError {
+    fn from(value: IdeviceError) -> Self {
+        LaunchError::Idevice(value)
+    }
+}
+
+impl From<std::io::Error> for LaunchError {
+    fn from(value: std::io::Error) -> Self {
+        LaunchError::Io(value)
+    }
+}
+
+/// Ports of the services we use, found through RSD
+struct DeviceServices {
+    address: IpAddr,
+    dvt_port: u16,
+    debug_port: u16,
+}
+
+impl DeviceServices {
+    async fn connect(udid: &str) -> Result<Self, LaunchError> {
+        let tunnel = find_tunnel(udid).await?;
+        let address = tunnel.address;
+        debug!("Found tunnel for {udid} at [{address}]:{}", tunnel.port);
+
+        let rsd = TcpStream::connect((address, tunnel.port)).await?;
+        let xpc = XPCDevice::new(Box::new(rsd)).await?;
+        let dvt_port = match xpc.services.get(DVT_SERVICE_NAME) {
+            Some(s) => s.port,
+            None => return Err(LaunchError::ServiceNotFound(DVT_SERVICE_NAME)),
+        };
+        let debug_port = match xpc.services.get(debugserver::SERVICE_NAME) {
+            Some(s) => s.port,
+            None => return Err(LaunchError::ServiceNotFound(debugserver::SERVICE_NAME)),
+        };
+
+        Ok(Self {
+            address,
+            dvt_port,
+            debug_port,
+        })
+    }
+
+    /// Connects to debugserver and puts it in the mode we always use
+    async fn debugserver(&self) -> Result<DebugserverClient<TcpStream>, LaunchError> {
+        info!(
+            "Connecting to debugserver at [{}]:{}",
+            self.address, self.debug_port
+        );
+        let debug_stream = TcpStream::connect((self.address, self.debug_port)).await?;
+        let mut debugserver = DebugserverClient::new(debug_stream);
+        debugserver.start_no_ack_mode().await?;
+        debugserver.set_detach_on_error().await?;
+        Ok(debugserver)
+    }
+
+    /// Attaching is what actually enables JIT for the process
+    async fn attach(
+        &self,
+        pid: u64,
+        job: &Progress,
+    ) -> Result<DebugserverClient<TcpStream>, LaunchError> {
+        let mut debugserver = self.debugserver().await?;
+        let stop = debugserver.attach(pid).await?;
+        if let StopReply::Signal { signal, thread, .. } = &stop {
+            debug!(
+                "Attached to {pid}, stopped with signal {signal:x} on thread {thread:?} ({})",
+                stop.field("reason").unwrap_or("no reason")
+            );
+        }
+        job.set_status(JobStatus::Attached, Some(pid));
+        Ok(debugserver)
+    }
+
+    async fn attach_and_detach(&self, pid: u64, job: &Progress) -> Result<(), LaunchError> {
+        self.attach(pid, job).await?.detach().await
+    }
+
+    async fn launch_suspended(
+        &self,
+        bundle_id: &str,
+        options: &LaunchOptions,
+        job: &Progress,
+    ) -> Result<u64, LaunchError> {
+        job.set_status(JobStatus::Launching, None);
+        let dvt_stream = TcpStream::connect((self.address, self.dvt_port)).await?;
+        let mut remote_server = RemoteServerClient::new(Box::new(dvt_stream));
+        remote_server.read_message(0).await?;
+        // idevice's ProcessControlClient only takes the arguments as a dictionary,
+        // so the launch is sent by hand with them as an array
+        let mut channel = remote_server.make_channel(PROCESS_CONTROL_CHANNEL).await?;
+        let (environment, arguments, launch_options) = process_control_options(options);
+        channel
+            .call_method(
+                Some(LAUNCH_METHOD),
+                Some(vec![
+                    AuxValue::archived_value("/private/"),
+                    AuxValue::archived_value(bundle_id),
+                    AuxValue::archived_value(environment),
+                    AuxValue::archived_value(arguments),
+                    AuxValue::archived_value(launch_options),
+                ]),
+                true,
+            )
+            .await?;
+        match channel.read_message().await?.data {
+            Some(plist::Value::Integer(pid)) => pid
+                .as_unsigned()
+                .ok_or(LaunchError::Idevice(IdeviceError::UnexpectedResponse)),
+            _ => Err(LaunchError::Idevice(IdeviceError::UnexpectedResponse)),
+        }
+    }
+
+    async fn running_processes(&self) -> Result<Vec<ProcessInfo>, LaunchError> {
+        let dvt_stream = TcpStream::connect((self.address, self.dvt_port)).await?;
+        let mut remote_server = RemoteServerClient::new(Box::new(dvt_stream));
+        remote_server.read_message(0).await?;
+        let mut channel = remote_server.make_channel(DEVICE_INFO_CHANNEL).await?;
+        channel
+            .call_method(Some("runningProcesses"), None, true)
+            .await?;
+        let processes = match channel.read_message().await?.data {
+            Some(plist::Value::Array(p)) => p,
+            _ => return Err(LaunchError::Idevice(IdeviceError::UnexpectedResponse)),
+        };
+
+        Ok(processes
+            .into_iter()
+            .filter_map(|p| {
+                let p = p.into_dictionary()?;
+                let path = p.get("realAppName")?.as_string()?.to_string();
+                if !path.contains(USER_APP_PATH) {
+                    return None;
+                }
+                Some(ProcessInfo {
+                    pid: p.get("pid")?.as_unsigned_integer()?,
+                    name: p.get("name")?.as_string()?.to_string(),
+                    path,
+                })
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProcessInfo {
+    pub pid: u64,
+    pub name: String,
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttachTarget {
+    Pid(u64),
+    Name(String),
+}
+
+/// Runs the work while the device is reachable, giving up after LAUNCH_TIMEOUT.
+/// That's while its tunnel is pinned, or while netmuxd is tracking it for tunneld.
+/// With keep_registered the device stays reachable if the work succeeds, until `release_device`.
+/// Dropping the work part way through lets go of the device too.
+async fn on_device<T>(
+    udid: &str,
+    ip: &str,
+    keep_registered: bool,
+    work: impl Future<Output = Result<T, LaunchError>>,
+) -> Result<T, LaunchError> {
+    let hold = match tunnel_mode() {
+        TunnelMode::Builtin => DeviceHold::Tunnel(
+            tunnels::open(udid, ip)
+                .await
+                .map_err(LaunchError::Tunnel)?
+                .1,
+        ),
+        TunnelMode::Tunneld => {
+            DeviceHold::Netmuxd(hold_device(udid, ip).await.ok_or(LaunchError::Netmuxd)?)
+        }
+    };
+
+    let res = match tokio::time::timeout(LAUNCH_TIMEOUT, work).await {
+        Ok(r) => r,
+        Err(_) => Err(LaunchError::Timeout),
+    };
+
+    if res.is_ok() && keep_registered {
+        hold.keep();
+    }
+    res
+}
+
+/// Lets go of a device kept reachable by a session or bridge
+pub async fn release_device(udid: &str) {
+    match tunnel_mode() {
+        TunnelMode::Builtin => tunnels::release(udid),
+        TunnelMode::Tunneld => {
+            if unhold(udid) {
+                netmuxd::remove_device(udid).await;
+            } else {
+                debug!("Keeping {udid} in netmuxd, it's still in use");
+            }
+        }
+    }
+}
+
+enum DeviceHold {
+    Tunnel(tunnels::TunnelPin),
+    Netmuxd(NetmuxdHold),
+}
+
+impl DeviceHold {
+    /// Leaves the device reachable after this is dropped, until `release_device`
+    fn keep(self) {
+        match self {
+            DeviceHold::Tunnel(pin) => pin.keep(),
+            DeviceHold::Netmuxd(hold) => hold.keep(),
+        }
+    }
+}
+
+/// Keeps the device tracked by netmuxd until dropped, or until `release_device` if kept.
+/// netmuxd stops tracking it once the last hold is gone.
+pub struct NetmuxdHold {
+    udid: Option<String>,
+}
+
+impl NetmuxdHold {
+    fn keep(mut self) {
+        self.udid = None;
+    }
+}
+
+impl Drop for NetmuxdHold {
+    fn drop(&mut self) {
+        if let Some(udid) = self.udid.take() {
+            if unhold(&udid) {
+                tokio::task::spawn(async move { netmuxd::remove_device(&udid).await });
+            } else {
+                debug!("Keeping {udid} in netmuxd, it's still in use");
+            }
+        }
+    }
+}
+
+/// Has netmuxd track the device and counts us as one more user of it
+pub async fn hold_device(udid: &str, ip: &str) -> Option<NetmuxdHold> {
+    add_hold(udid);
+    if netmuxd::add_device(ip, udid).await {
+        Some(NetmuxdHold {
+            udid: Some(udid.to_string()),
+        })
+    } else {
+        unhold(udid);
+        None
+    }
+}
+
+fn add_hold(udid: &str) {
+    *NETMUXD_HOLDS
+        .lock()
+        .unwrap()
+        .entry(udid.to_string())
+        .or_default() += 1;
+}
+
+/// Drops a hold, returning whether it was the last one
+fn unhold(udid: &str) -> bool {
+    let mut holds = NETMUXD_HOLDS.lock().unwrap();
+    match holds.get_mut(udid) {
+        Some(n) if *n > 1 => {
+            *n -= 1;
+            false
+        }
+        _ => {
+            holds.remove(udid);
+            true
+        }
+    }
+}
+
+/// Launches the app suspended, attaches debugserver and detaches again.
+/// Returns the PID of the launched app. Progress is recorded on the job.
+pub async fn launch_app(
+    udid: &str,
+    ip: &str,
+    bundle_id: &str,
+    options: &LaunchOptions,
+    job: &Progress,
+) -> Result<u64, LaunchError> {
+    on_device(udid, ip, false, async {
+        let services = DeviceServices::connect(udid).await?;
+        let pid = services.launch_suspended(bundle_id, options, job).await?;
+        info!("Launched {bundle_id} on {udid} with PID {pid}");
+
+        services.attach_and_detach(pid, job).await?;
+        Ok(pid)
+    })
+    .await
+}
+
+/// Launches the app suspended and attaches debugserver, leaving it attached.
+/// The device stays reachable for the caller's session, see session.rs.
+pub async fn launch_session(
+    udid: &str,
+    ip: &str,
+    bundle_id: &str,
+    options: &LaunchOptions,
+    job: &Progress,
+) -> Result<(u64, DebugserverClient<TcpStream>), LaunchError> {
+    on_device(udid, ip, true, async {
+        let services = DeviceServices::connect(udid).await?;
+        let pid = services.launch_suspended(bundle_id, options, job).await?;
+        info!("Launched {bundle_id} on {udid} with PID {pid} for a debug session");
+
+        Ok((pid, services.attach(pid, job).await?))
+    })
+    .await
+}
+
+/// Enables JIT for a process that is already running, without relaunching it.
+/// Returns the PID that was attached to.
+pub async fn attach(
+    udid: &str,
+    ip: &str,
+    target: &AttachTarget,
+    job: &Progress,
+) -> Result<u64, LaunchError> {
+    on_device(udid, ip, false, async {
+        let services = DeviceServices::connect(udid).await?;
+        let pid = match target {
+            AttachTarget::Pid(pid) => *pid,
+            AttachTarget::Name(name) => match services
+                .running_processes()
+                .await?
+                .into_iter()
+                .find(|p| &p.name == name)
+            {
+                Some(p) => p.pid,
+                None => return Err(LaunchError::ProcessNotFound(name.clone())),
+            },
+        };
+
+        services.attach_and_detach(pid, job).await?;
+        info!("Attached to {pid} on {udid}");
+        Ok(pid)
+    })
+    .await
+}
+
+/// Connects to the device's debugproxy for a client that speaks GDB remote itself, see lldb.rs.
+/// The device stays reachable, the caller releases it with `release_device` when done.
+pub async fn connect_debugproxy(udid: &str, ip: &str) -> Result<TcpStream, LaunchError> {
+    on_device(udid, ip, true, async {
+        let services = DeviceServices::connect(udid).await?;
+        Ok(TcpStream::connect((services.address, services.debug_port)).await?)
+    })
+    .await
+}
+
+/// Lists the running processes that belong to user installed apps
+pub async fn list_processes(udid: &str, ip: &str) -> Result<Vec<ProcessInfo>, LaunchError> {
+    on_device(udid, ip, false, async {
+        DeviceServices::connect(udid)
+            .await?
+            .running_processes()
+            .await
+    })
+    .await
+}
+
+/// Finds the tunnel to the device's RSD port
+async fn find_tunnel(udid: &str) -> Result<Tunnel, LaunchError> {
+    match tunnel_mode() {
+        TunnelMode::Builtin => tunnels::tunnel(udid).ok_or(LaunchError::TunnelNotFound),
+        TunnelMode::Tunneld => {
+            // Give tunneld a moment to notice the device
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            get_tunnel(udid).await
+        }
+    }
+}
+
+/// Asks tunneld for the RSD address of the device, retrying while the tunnel comes up
+async fn get_tunnel(udid: &str) -> Result<Tunnel, LaunchError> {
+    match TunneldClient::from_env()
+        .wait_for_tunnel(udid, 15, Duration::from_secs(1))
+        .await
+    {
+        Ok(Some(tunnel)) => Ok(tunnel),
+        Ok(None) => Err(LaunchError::TunnelNotFound),
+        Err(e) => Err(LaunchError::Tunneld(e)),
+    }
+}
+
+/// Registers the device long enough to find its tunnel and check the RSD port answers.
+/// Returns the tunnel and whether it's healthy.
+pub async fn check_tunnel(udid: &str, ip: &str) -> Result<(Tunnel, bool), LaunchError> {
+    on_device(udid, ip, false, async {
+        let tunnel = find_tunnel(udid).await?;
+        let healthy = tunnel.is_healthy().await;
+        Ok((tunnel, healthy))
+    })
+    .await
+}
+
+/// Converts the queued options into what process control expects.
+/// The arguments are an array, like Xcode and pymobiledevice3 send them.
+fn process_control_options(
+    options: &LaunchOptions,
+) -> (plist::Dictionary, plist::Value, plist::Dictionary) {
+    let environment = options
+        .environment
+        .iter()
+        .map(|(k, v)| (k.clone(), plist::Value::String(v.clone())))
+        .collect();
+    let arguments = plist::Value::Array(
+        options
+            .arguments
+            .iter()
+            .map(|a| plist::Value::String(a.clone()))
+            .collect(),
+    );
+    let mut launch_options = plist::Dictionary::new();
+    launch_options.insert("StartSuspendedKey".into(), true.into());
+    launch_options.insert("KillExisting".into(), options.kill_existing.into());
+    (environment, arguments, launch_options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_netmuxd_holds() {
+        add_hold("holds-udid");
+        add_hold("holds-udid");
+        assert!(!unhold("holds-udid"));
+        assert!(unhold("holds-udid"));
+        assert!(!NETMUXD_HOLDS.lock().unwrap().contains_key("holds-udid"));
+    }
+
+    #[test]
+    fn builds_process_control_options() {
+        let options = LaunchOptions {
+            arguments: vec!["-v".into(), "--mode".into(), "jit".into()],
+            environment: [("LOG".to_string(), "1".to_string())].into(),
+            kill_existing: true,
+            ..Default::default()
+        };
+        let (environment, arguments, launch_options) = process_control_options(&options);
+        assert_eq!(
+            environment.get("LOG"),
+            Some(&plist::Value::String("1".into()))
+        );
+        assert_eq!(
+            arguments,
+            plist::Value::Array(vec!["-v".into(), "--mode".into(), "jit".into()])
+        );
+        assert_eq!(
+            launch_options.get("KillExisting"),
+            Some(&plist::Value::Boolean(true))
+        );
+
+        let (environment, arguments, _) = process_control_options(&LaunchOptions::default());
+        assert!(environment.is_empty());
+        assert_eq!(arguments, plist::Value::Array(Vec::new()));
+    }
+}
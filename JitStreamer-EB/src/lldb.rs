@@ -0,0 +1,379 @@
+// Jackson Coxson
+// Bridges a developer's lldb to their device's debugproxy.
+// The device asks for a bridge and gets a one-time token. Whoever holds the token,
+// usually the developer's own machine, opens the bridge with it.
+// Bridges are closed after LLDB_BRIDGE_MINUTES.
+// TCP bridges listen on a port from LLDB_BRIDGE_PORTS, so they can be let through a firewall.
+
+use std::{
+    collections::HashMap,
+    io::Read,
+    net::IpAddr,
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        Path, State, WebSocketUpgrade,
+    },
+    Json,
+};
+use axum_client_ip::SecureClientIp;
+use log::{debug, info, warn};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{common, heartbeat, launch, JitStreamerState};
+
+static NEXT_BRIDGE: AtomicU64 = AtomicU64::new(0);
+
+/// Bridges the device asked for that nobody has opened yet, by token
+static PENDING: LazyLock<Mutex<HashMap<String, PendingBridge>>> = LazyLock::new(Default::default);
+
+/// How long the token is good for, and how long the client has to connect to a TCP bridge
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct PendingBridge {
+    udid: String,
+    /// The device's VPN address
+    ip: IpAddr,
+    created: Instant,
+}
+
+fn bridge_duration() -> Duration {
+    let minutes = std::env::var("LLDB_BRIDGE_MINUTES")
+        .unwrap_or("30".to_string())
+        .parse::<u64>()
+        .unwrap_or(30);
+    Duration::from_secs(minutes * 60)
+}
+
+const DEFAULT_BRIDGE_PORTS: RangeInclusive<u16> = 9200..=9219;
+
+fn bridge_ports() -> RangeInclusive<u16> {
+    match std::env::var("LLDB_BRIDGE_PORTS") {
+        Ok(ports) => parse_ports(&ports).unwrap_or_else(|| {
+            warn!("LLDB_BRIDGE_PORTS should look like 9200-9219, not {ports}");
+            DEFAULT_BRIDGE_PORTS
+        }),
+        Err(_) => DEFAULT_BRIDGE_PORTS,
+    }
+}
+
+/// A range like `9200-9219`, or a single port
+fn parse_ports(ports: &str) -> Option<RangeInclusive<u16>> {
+    let (start, end) = ports.split_once('-').unwrap_or((ports, ports));
+    let (start, end) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+    (start <= end).then_some(start..=end)
+}
+
+/// Listens on the first free port in the range
+async fn bind_bridge(ports: RangeInclusive<u16>) -> std::io::Result<TcpListener> {
+    for port in ports {
+        match TcpListener::bind(("::", port)).await {
+            Ok(l) => return Ok(l),
+            Err(e) => debug!("lldb bridge port {port} is taken: {e}"),
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::AddrInUse,
+        "every lldb bridge port is in use",
+    ))
+}
+
+fn new_token() -> std::io::Result<String> {
+    let mut bytes = [0u8; 16];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Takes the pending bridge for the token, it can only be used once
+fn take_pending(token: &str) -> Option<PendingBridge> {
+    let mut pending = PENDING.lock().unwrap();
+    pending.retain(|_, p| p.created.elapsed() < ACCEPT_TIMEOUT);
+    pending.remove(token)
+}
+
+#[derive(Serialize)]
+pub struct LldbTokenReturn {
+    ok: bool,
+    token: Option<String>,
+    expires_in: Option<u64>,
+    error: Option<String>,
+}
+
+/// Called from the device, returns the token that opens a bridge to it
+pub async fn request_bridge(ip: SecureClientIp) -> Json<LldbTokenReturn> {
+    let ip = ip.0;
+    info!("Got request for an lldb bridge from {ip:?}");
+
+    let udid = match common::get_udid_from_ip(ip.to_string()).await {
+        Ok(u) => u,
+        Err(e) => {
+            return Json(LldbTokenReturn {
+                ok: false,
+                token: None,
+                expires_in: None,
+                error: Some(e),
+            })
+        }
+    };
+
+    let token = match new_token() {
+        Ok(t) => t,
+        Err(e) => {
+            warn!("Failed to generate an lldb bridge token: {e:?}");
+            return Json(LldbTokenReturn {
+                ok: false,
+                token: None,
+                expires_in: None,
+                error: Some("Failed to generate a token".to_string()),
+            });
+        }
+    };
+    PENDING.lock().unwrap().insert(
+        token.clone(),
+        PendingBridge {
+            udid,
+            ip,
+            created: Instant::now(),
+        },
+    );
+
+    Json(LldbTokenReturn {
+        ok: true,
+        token: Some(token),
+        expires_in: Some(ACCEPT_TIMEOUT.as_secs()),
+        error: None,
+    })
+}
+
+#[derive(Serialize)]
+pub struct LldbBridgeReturn {
+    ok: bool,
+    port: Option<u16>,
+    expires_in: Option<u64>,
+    error: Option<String>,
+}
+
+/// Opens a one-shot TCP port for `gdb-remote`.
+/// Only the IP that redeemed the token may connect, and only within ACCEPT_TIMEOUT.
+pub async fn tcp_bridge(
+    ip: SecureClientIp,
+    Path(token): Path<String>,
+    State(state): State<JitStreamerState>,
+) -> Json<LldbBridgeReturn> {
+    let client_ip = ip.0;
+    let Some(pending) = take_pending(&token) else {
+        return Json(LldbBridgeReturn {
+            ok: false,
+            port: None,
+            expires_in: None,
+            error: Some("Unknown or expired token".to_string()),
+        });
+    };
+    let PendingBridge { udid, ip, .. } = pending;
+    info!("Opening lldb bridge to {udid} for {client_ip}");
+
+    let listener = match bind_bridge(bridge_ports()).await {
+        Ok(l) => l,
+        Err(e) => {
+            warn!("Failed to bind lldb bridge: {e:?}");
+            return Json(LldbBridgeReturn {
+                ok: false,
+                port: None,
+                expires_in: None,
+                error: Some("Failed to open bridge port".to_string()),
+            });
+        }
+    };
+    let port = listener.local_addr().unwrap().port();
+
+    tokio::task::spawn(async move {
+        let accept = async {
+            loop {
+                match listener.accept().await {
+                    Ok((client, addr)) if addr.ip().to_canonical() == client_ip.to_canonical() => {
+                        return Some(client)
+                    }
+                    Ok((_, addr)) => warn!("Rejected lldb bridge connection from {addr}"),
+                    Err(e) => {
+                        warn!("Failed to accept lldb bridge connection: {e:?}");
+                        return None;
+                    }
+                }
+            }
+        };
+        let client = match tokio::time::timeout(ACCEPT_TIMEOUT, accept).await {
+            Ok(Some(c)) => c,
+            Ok(None) => return,
+            Err(_) => {
+                info!("Nobody connected to the lldb bridge for {udid}");
+                return;
+            }
+        };
+        bridge(state, udid, ip, |device| pump_tcp(client, device)).await;
+    });
+
+    Json(LldbBridgeReturn {
+        ok: true,
+        port: Some(port),
+        expires_in: Some(bridge_duration().as_secs()),
+        error: None,
+    })
+}
+
+/// Same bridge, but with the GDB remote traffic in binary WebSocket frames
+pub async fn ws_bridge(
+    ws: WebSocketUpgrade,
+    Path(token): Path<String>,
+    State(state): State<JitStreamerState>,
+) -> axum::response::Response {
+    let pending = take_pending(&token);
+    ws.on_upgrade(move |mut socket| async move {
+        let Some(PendingBridge { udid, ip, .. }) = pending else {
+            socket.send(Message::Close(None)).await.ok();
+            info!("Refused lldb bridge with an unknown token");
+            return;
+        };
+        bridge(state, udid, ip, |device| pump_ws(socket, device)).await;
+    })
+}
+
+/// Connects to the device and runs the pump until either side closes or time runs out.
+/// The device is heartbeated for as long as the bridge is open.
+async fn bridge<F, Fut>(state: JitStreamerState, udid: String, ip: IpAddr, pump: F)
+where
+    F: FnOnce(TcpStream) -> Fut,
+    Fut: std::future::Future<Output = std::io::Result<()>>,
+{
+    let device = match launch::connect_debugproxy(&udid, &ip.to_string()).await {
+        Ok(d) => d,
+        Err(e) => {
+            warn!("Failed to open lldb bridge for {udid}: {e}");
+            return;
+        }
+    };
+    info!("Opened lldb bridge for {udid}");
+    let owner = heartbeat::Owner::Bridge(NEXT_BRIDGE.fetch_add(1, Ordering::Relaxed));
+
+    match common::get_pairing_file(&udid).await {
+        Ok(pairing_file) => {
+            match heartbeat::heartbeat_thread(udid.clone(), ip, &pairing_file).await {
+                Ok(s) => {
+                    state
+                        .new_heartbeat_sender
+                        .send(heartbeat::SendRequest::Store((
+                            udid.clone(),
+                            owner.clone(),
+                            s,
+                        )))
+                        .await
+                        .ok();
+                }
+                Err(e) => warn!("Failed to heartbeat {udid} for the lldb bridge: {e:?}"),
+            }
+        }
+        Err(e) => warn!("Failed to get pairing file for {udid}: {e:?}"),
+    }
+
+    match tokio::time::timeout(bridge_duration(), pump(device)).await {
+        Ok(Ok(())) => info!("lldb bridge for {udid} closed"),
+        Ok(Err(e)) => info!("lldb bridge for {udid} closed: {e:?}"),
+        Err(_) => info!("lldb bridge for {udid} expired"),
+    }
+
+    state
+        .new_heartbeat_sender
+        .send(heartbeat::SendRequest::Kill((udid.clone(), owner)))
+        .await
+        .ok();
+    launch::release_device(&udid).await;
+}
+
+async fn pump_tcp(mut client: TcpStream, mut device: TcpStream) -> std::io::Result<()> {
+    let (up, down) = tokio::io::copy_bidirectional(&mut client, &mut device).await?;
+    debug!("lldb bridge moved {up} bytes up and {down} bytes down");
+    Ok(())
+}
+
+async fn pump_ws(mut socket: WebSocket, device: TcpStream) -> std::io::Result<()> {
+    let (mut reader, mut writer) = device.into_split();
+    let mut buf = vec![0u8; 4096];
+    loop {
+        tokio::select! {
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Binary(b))) => writer.write_all(&b).await?,
+                Some(Ok(Message::Text(t))) => writer.write_all(t.as_bytes()).await?,
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    debug!("lldb bridge websocket failed: {e:?}");
+                    return Ok(());
+                }
+            },
+            n = reader.read(&mut buf) => {
+                let n = n?;
+                if n == 0 {
+                    socket.send(Message::Close(None)).await.ok();
+                    return Ok(());
+                }
+                if socket.send(Message::Binary(buf[..n].to_vec().into())).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_single_use() {
+        let token = new_token().unwrap();
+        assert_eq!(token.len(), 32);
+        assert_ne!(token, new_token().unwrap());
+
+        PENDING.lock().unwrap().insert(
+            token.clone(),
+            PendingBridge {
+                udid: "udid".to_string(),
+                ip: "10.7.0.2".parse().unwrap(),
+                created: Instant::now(),
+            },
+        );
+        assert_eq!(take_pending(&token).unwrap().udid, "udid");
+        assert!(take_pending(&token).is_none());
+    }
+
+    #[test]
+    fn parses_port_ranges() {
+        assert_eq!(parse_ports("9200-9219"), Some(9200..=9219));
+        assert_eq!(parse_ports("9200"), Some(9200..=9200));
+        assert_eq!(parse_ports(" 9200 - 9201 "), Some(9200..=9201));
+        assert_eq!(parse_ports("9219-9200"), None);
+        assert_eq!(parse_ports("9200-"), None);
+        assert_eq!(parse_ports("lots"), None);
+    }
+
+    #[tokio::test]
+    async fn binds_within_the_range() {
+        let taken = TcpListener::bind(("::", 0)).await.unwrap();
+        let port = taken.local_addr().unwrap().port();
+        assert!(bind_bridge(port..=port).await.is_err());
+        drop(taken);
+        let listener = bind_bridge(port..=port).await.unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), port);
+    }
+}
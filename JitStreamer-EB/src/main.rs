@@ -7,13 +7,14 @@ use std::{
     collections::HashMap,
     net::{IpAddr, SocketAddr},
     str::FromStr,
+    sync::Arc,
 };
 
 use axum::{
-    extract::{Json, Path, State},
+    extract::{FromRef, Json, Path, State},
     http::{header::CONTENT_TYPE, Method},
     response::Html,
-    routing::{any, get, post},
+    routing::{any, delete, get, post},
 };
 use axum_client_ip::SecureClientIp;
 use common::get_pairing_file;
@@ -23,18 +24,40 @@ use log::{debug, info};
 use serde::{Deserialize, Serialize};
 use tower_http::cors::CorsLayer;
 
+mod admin;
+mod backend;
 mod common;
 mod db;
+mod ddi;
 mod debug_server;
+mod debugserver;
+mod gdb;
 mod heartbeat;
+mod launch;
+mod lldb;
 mod mount;
+mod netmuxd;
 mod register;
 mod runner;
-
-#[derive(Clone)]
-struct JitStreamerState {
+mod session;
+mod status_ws;
+mod tss;
+mod tunneld;
+mod tunnels;
+mod worker;
+
+#[derive(Clone, FromRef)]
+pub struct JitStreamerState {
     pub new_heartbeat_sender: NewHeartbeatSender,
     pub mount_cache: mount::MountCache,
+    pub mount_queue: Arc<mount::MountQueue>,
+    pub sessions: session::Sessions,
+    pub cancellations: runner::Cancellations,
+    pub workers: runner::Workers,
+    pub backends: Arc<backend::Backends>,
+    pub ddi: Arc<ddi::DdiStore>,
+    pub tickets: Arc<tss::TicketCache>,
+    pub worker_secret: worker::WorkerSecret,
 }
 
 #[tokio::main]
@@ -60,6 +83,37 @@ async fn main() {
     env_logger::init();
     info!("Logger initialized");
 
+    match launch::TunnelMode::from_env() {
+        Ok(mode) => launch::set_tunnel_mode(mode),
+        Err(e) => {
+            log::error!("{e}");
+            return;
+        }
+    }
+
+    // Close the built in tunnels devices are done with
+    if launch::tunnel_mode() == launch::TunnelMode::Builtin {
+        tokio::task::spawn(tunnels::maintain());
+    }
+
+    // Work for another server instead of serving
+    if let Ok(url) = std::env::var("WORKER_SERVER_URL") {
+        let secret = std::env::var(worker::SECRET_VAR).expect("WORKER_SECRET is required");
+        tokio::select! {
+            _ = worker::run_remote(url, secret, runner_count) => {}
+            _ = shutdown_signal() => {}
+        }
+        return;
+    }
+
+    let backends = match backend::Backends::from_env() {
+        Ok(b) => b,
+        Err(e) => {
+            log::error!("Bad launch backend configuration: {e}");
+            return;
+        }
+    };
+
     // Run the environment checks
     if allow_registration {
         register::check_wireguard();
@@ -69,21 +123,50 @@ async fn main() {
         let db = sqlite::open("jitstreamer.db").unwrap();
         db.execute(include_str!("sql/up.sql")).unwrap();
     }
+    if let Err(e) = db::migrate(&sqlite::open("jitstreamer.db").unwrap()) {
+        log::error!("{e}");
+        return;
+    }
 
-    // Empty the queues
-    debug_server::empty().await;
+    // Pick up the launches left over from the last run
+    debug_server::restore();
 
     // Create a heartbeat manager
     let state = JitStreamerState {
         new_heartbeat_sender: heartbeat::heartbeat(),
         mount_cache: mount::MountCache::default(),
+        mount_queue: Arc::new(mount::MountQueue::from_env()),
+        sessions: session::Sessions::default(),
+        cancellations: runner::Cancellations::default(),
+        workers: runner::Workers::default(),
+        backends: Arc::new(backends),
+        ddi: Arc::new(ddi::DdiStore::from_env()),
+        tickets: Arc::new(tss::TicketCache::default()),
+        worker_secret: worker::WorkerSecret::from_env(),
     };
 
-    // Run the Python shims
-    runner::run("src/runners/launch.py", runner_count);
+    // Load the developer disk images, and again whenever we're sent SIGHUP
+    if let Err(e) = state.ddi.reload().await {
+        log::warn!("Failed to load developer disk images: {e}");
+    }
+    tokio::task::spawn(reload_on_hangup(state.ddi.clone()));
+
+    // Clean up mounts that were abandoned or stalled
+    tokio::task::spawn(mount::maintain(
+        state.mount_cache.clone(),
+        state.mount_queue.clone(),
+    ));
+
+    // Start the launch workers
+    let workers = runner::run(runner_count, state.clone());
+
+    // Keep track of what netmuxd is doing for /admin/netmuxd
+    if state.backends.uses_tunneld() {
+        tokio::task::spawn(netmuxd::watch());
+    }
 
     let cors = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
+        .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::OPTIONS])
         .allow_origin(tower_http::cors::Any)
         .allow_headers([CONTENT_TYPE]);
 
@@ -99,8 +182,36 @@ async fn main() {
             get(|| async { Html(include_str!("mount.html")) }),
         )
         .route("/get_apps", get(get_apps))
+        .route(
+            "/launch_app",
+            post(launch_app_with_options).delete(cancel_launches),
+        )
         .route("/launch_app/{bundle_id}", get(launch_app))
+        .route("/processes", get(processes))
+        .route("/attach/{target}", get(attach))
         .route("/status", get(status))
+        .route("/status/{job_id}", get(job_status).delete(cancel_job))
+        .route("/status_ws", any(status_ws::handler))
+        .route("/status_ws/{job_id}", any(status_ws::job_handler))
+        .route("/session", get(session::status))
+        .route("/tunnel", get(tunneld::check))
+        .route("/lldb", post(lldb::request_bridge))
+        .route("/lldb/{token}", post(lldb::tcp_bridge))
+        .route("/lldb_ws/{token}", any(lldb::ws_bridge))
+        .route("/admin/workers", get(admin::workers))
+        .route("/admin/backends", get(admin::backends))
+        .route("/admin/netmuxd", get(admin::netmuxd_devices))
+        .route("/admin/tunnels", get(admin::tunnels))
+        .route("/admin/ddi", get(admin::ddi))
+        .route("/admin/ddi/reload", post(admin::reload_ddi))
+        .route("/admin/mounts", get(admin::mounts))
+        .route("/admin/mounts/{udid}", delete(admin::forget_mount))
+        .route("/worker/launch", post(backend::remote_launch))
+        .route("/worker/claim", post(worker::claim))
+        .route("/worker/heartbeat", post(worker::heartbeat))
+        .route("/worker/progress", post(worker::progress))
+        .route("/worker/complete", post(worker::complete))
+        .route("/worker/fail", post(worker::fail))
         .with_state(state);
 
     let app = if allow_registration {
@@ -120,8 +231,59 @@ async fn main() {
         listener,
         app.into_make_service_with_connect_info::<SocketAddr>(),
     )
+    .with_graceful_shutdown(shutdown_signal())
     .await
     .unwrap();
+
+    // Let the workers finish what they're doing
+    workers.shutdown().await;
+}
+
+/// Resolves on Ctrl+C or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.ok();
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut s) => {
+                s.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutting down");
+}
+
+/// Reloads the developer disk images on SIGHUP, so new ones don't need a restart
+async fn reload_on_hangup(store: Arc<ddi::DdiStore>) {
+    #[cfg(unix)]
+    {
+        let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        {
+            Ok(s) => s,
+            Err(e) => {
+                log::warn!("Failed to listen for SIGHUP: {e}");
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            info!("Got SIGHUP, reloading developer disk images");
+            if let Err(e) = store.reload().await {
+                log::warn!("Failed to reload developer disk images: {e}");
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = store;
 }
 
 #[derive(Serialize, Deserialize)]
@@ -206,7 +368,11 @@ async fn get_apps(
         Ok(s) => {
             state
                 .new_heartbeat_sender
-                .send(heartbeat::SendRequest::Store((udid.clone(), s)))
+                .send(heartbeat::SendRequest::Store((
+                    udid.clone(),
+                    heartbeat::Owner::Apps,
+                    s,
+                )))
                 .await
                 .unwrap();
         }
@@ -305,7 +471,10 @@ async fn get_apps(
 
     state
         .new_heartbeat_sender
-        .send(heartbeat::SendRequest::Kill(udid.clone()))
+        .send(heartbeat::SendRequest::Kill((
+            udid.clone(),
+            heartbeat::Owner::Apps,
+        )))
         .await
         .unwrap();
 
@@ -317,12 +486,55 @@ async fn get_apps(
     })
 }
 
+#[derive(Serialize)]
+struct ProcessesReturn {
+    ok: bool,
+    processes: Vec<launch::ProcessInfo>,
+    error: Option<String>,
+}
+
+/// Lists the running user processes, to pick one for /attach
+async fn processes(ip: SecureClientIp) -> Json<ProcessesReturn> {
+    let ip = ip.0;
+
+    info!("Got request to list processes from {:?}", ip);
+
+    let udid = match common::get_udid_from_ip(ip.to_string()).await {
+        Ok(u) => u,
+        Err(e) => {
+            return Json(ProcessesReturn {
+                ok: false,
+                processes: Vec::new(),
+                error: Some(e),
+            })
+        }
+    };
+
+    match launch::list_processes(&udid, &ip.to_string()).await {
+        Ok(processes) => Json(ProcessesReturn {
+            ok: true,
+            processes,
+            error: None,
+        }),
+        Err(e) => {
+            info!("Failed to list processes for {udid}: {e}");
+            Json(ProcessesReturn {
+                ok: false,
+                processes: Vec::new(),
+                error: Some(format!("Failed to list processes: {e}")),
+            })
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct LaunchAppReturn {
     ok: bool,
     launching: bool,
     position: Option<usize>,
+    job_id: Option<i64>,
     error: Option<String>,
+    error_code: Option<String>,
     mounting: bool, // NOTICE: this field does literally nothing and will be removed in future
                     // versions
 }
@@ -334,10 +546,66 @@ struct LaunchAppReturn {
 ///  - Connect to tunneld and get the interface and port for the developer service
 ///  - Send the commands to launch the app and detach
 ///  - Set last_used to now in the database
-async fn launch_app(ip: SecureClientIp, Path(bundle_id): Path<String>) -> Json<LaunchAppReturn> {
-    let ip = ip.0;
+async fn launch_app(
+    ip: SecureClientIp,
+    State(state): State<JitStreamerState>,
+    Path(bundle_id): Path<String>,
+) -> Json<LaunchAppReturn> {
+    queue_launch(
+        &state,
+        ip.0,
+        debug_server::JobKind::Launch {
+            bundle_id,
+            options: debug_server::LaunchOptions::default(),
+        },
+    )
+    .await
+}
 
-    info!("Got request to launch {bundle_id} from {:?}", ip);
+#[derive(Deserialize)]
+struct LaunchAppRequest {
+    bundle_id: String,
+    #[serde(flatten)]
+    options: debug_server::LaunchOptions,
+}
+
+/// Same as launch_app, but takes the arguments, environment and flags to launch with
+async fn launch_app_with_options(
+    ip: SecureClientIp,
+    State(state): State<JitStreamerState>,
+    Json(request): Json<LaunchAppRequest>,
+) -> Json<LaunchAppReturn> {
+    queue_launch(
+        &state,
+        ip.0,
+        debug_server::JobKind::Launch {
+            bundle_id: request.bundle_id,
+            options: request.options,
+        },
+    )
+    .await
+}
+
+/// Enables JIT for a process that's already running, without relaunching it.
+/// The target is either a PID or a process name from /processes.
+async fn attach(
+    ip: SecureClientIp,
+    State(state): State<JitStreamerState>,
+    Path(target): Path<String>,
+) -> Json<LaunchAppReturn> {
+    let target = match target.parse::<u64>() {
+        Ok(pid) => launch::AttachTarget::Pid(pid),
+        Err(_) => launch::AttachTarget::Name(target),
+    };
+    queue_launch(&state, ip.0, debug_server::JobKind::Attach(target)).await
+}
+
+async fn queue_launch(
+    state: &JitStreamerState,
+    ip: IpAddr,
+    job: debug_server::JobKind,
+) -> Json<LaunchAppReturn> {
+    info!("Got request to {job} from {:?}", ip);
 
     let udid = match common::get_udid_from_ip(ip.to_string()).await {
         Ok(u) => u,
@@ -345,63 +613,46 @@ async fn launch_app(ip: SecureClientIp, Path(bundle_id): Path<String>) -> Json<L
             return Json(LaunchAppReturn {
                 ok: false,
                 error: Some(e),
+                error_code: None,
                 launching: false,
                 position: None,
+                job_id: None,
                 mounting: false,
             })
         }
     };
 
-    // Check if there are any launches queued
-    debug!("Checking launch queue for {udid}");
-    match debug_server::get_queue_info(&udid).await {
-        debug_server::LaunchQueueInfo::Position(p) => {
-            return Json(LaunchAppReturn {
-                ok: true,
-                launching: true,
-                position: Some(p),
-                error: None,
-                mounting: false,
-            });
-        }
-        debug_server::LaunchQueueInfo::NotInQueue => {}
-        debug_server::LaunchQueueInfo::Error(e) => {
+    // Without tunneld the launch would sit in the queue only to fail
+    if state.backends.uses_tunneld() {
+        if let Err(e) = tunneld::TunneldClient::from_env().list().await {
+            let e = launch::LaunchError::Tunneld(e);
+            log::error!("Not queueing launch for {udid}: {e}");
             return Json(LaunchAppReturn {
                 ok: false,
+                error: Some(e.to_string()),
+                error_code: Some(e.code().to_string()),
                 launching: false,
                 position: None,
-                error: Some(e),
-                mounting: false,
-            });
-        }
-        debug_server::LaunchQueueInfo::ServerError => {
-            return Json(LaunchAppReturn {
-                ok: false,
-                launching: false,
-                position: None,
-                error: Some("Failed to get launch status".to_string()),
+                job_id: None,
                 mounting: false,
             });
         }
     }
 
+    // Older failures were for older launches, /status should report on this one
+    debug_server::acknowledge_failures(&udid);
+
     // Add the launch to the queue
-    match debug_server::add_to_queue(&udid, ip.to_string(), &bundle_id).await {
-        Some(position) => Json(LaunchAppReturn {
-            ok: true,
-            launching: true,
-            position: Some(position as usize),
-            error: None,
-            mounting: false,
-        }),
-        None => Json(LaunchAppReturn {
-            ok: false,
-            launching: false,
-            position: None,
-            error: Some("Failed to add to queue".to_string()),
-            mounting: false,
-        }),
-    }
+    let (job_id, position) = debug_server::add_to_queue(&udid, ip.to_string(), &job);
+    Json(LaunchAppReturn {
+        ok: true,
+        launching: true,
+        position: Some(position),
+        job_id: Some(job_id),
+        error: None,
+        error_code: None,
+        mounting: false,
+    })
 }
 
 #[derive(Debug, Serialize)]
@@ -413,9 +664,37 @@ struct StatusReturn {
     in_progress: bool, // NOTICE: this field is deprecated and will be removed in future versions
 }
 
+impl From<debug_server::LaunchQueueInfo> for StatusReturn {
+    fn from(info: debug_server::LaunchQueueInfo) -> Self {
+        match info {
+            debug_server::LaunchQueueInfo::Position(p) => StatusReturn {
+                ok: true,
+                done: false,
+                position: p,
+                error: None,
+                in_progress: false,
+            },
+            debug_server::LaunchQueueInfo::NotInQueue => StatusReturn {
+                ok: true,
+                done: true,
+                position: 0,
+                error: None,
+                in_progress: false,
+            },
+            debug_server::LaunchQueueInfo::Error(e) => StatusReturn {
+                ok: false,
+                done: true,
+                position: 0,
+                error: Some(e),
+                in_progress: false,
+            },
+        }
+    }
+}
+
 /// Gets the current status of the device
 /// Returns immediately if done or error
-/// Checks every second, up to 15 seconds for a new response.
+/// Otherwise waits up to 15 seconds for the queue to change.
 async fn status(ip: SecureClientIp) -> Json<StatusReturn> {
     let start_time = std::time::Instant::now();
     let ip = ip.0;
@@ -433,61 +712,187 @@ async fn status(ip: SecureClientIp) -> Json<StatusReturn> {
         }
     };
 
+    let mut changes = debug_server::subscribe();
     loop {
-        // Check mounts
-        // Check launches
-        // Check if it's been too long
-        let mut to_return = None;
-        match debug_server::get_queue_info(&udid).await {
-            debug_server::LaunchQueueInfo::Position(p) => {
-                to_return = Some(Json(StatusReturn {
-                    ok: true,
-                    done: false,
-                    position: p,
-                    error: None,
-                    in_progress: false,
-                }));
-            }
-            debug_server::LaunchQueueInfo::NotInQueue => {}
-            debug_server::LaunchQueueInfo::Error(e) => {
-                to_return = Some(Json(StatusReturn {
-                    ok: false,
-                    done: true,
-                    position: 0,
-                    error: Some(e),
-                    in_progress: false,
-                }));
-            }
-            debug_server::LaunchQueueInfo::ServerError => {
-                to_return = Some(Json(StatusReturn {
-                    ok: false,
-                    done: true,
-                    position: 0,
-                    error: Some("server error".to_string()),
-                    in_progress: false,
-                }));
+        changes.borrow_and_update();
+        let to_return = StatusReturn::from(debug_server::get_queue_info(&udid));
+        let remaining = std::time::Duration::from_secs(15).saturating_sub(start_time.elapsed());
+        if remaining.is_zero() || to_return.done {
+            info!("Returning status for {udid}: {to_return:?}");
+            return Json(to_return);
+        }
+        match tokio::time::timeout(remaining, changes.changed()).await {
+            Ok(Ok(())) => {}
+            _ => {
+                info!("Returning status for {udid}: {to_return:?}");
+                return Json(to_return);
             }
         }
+    }
+}
 
-        match to_return {
-            Some(to_return) => {
-                if start_time.elapsed() > std::time::Duration::from_secs(15) || to_return.done {
-                    info!("Returning status for {udid}: {to_return:?}");
-                    return to_return;
-                }
-                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-            }
-            None => {
-                if to_return.is_none() {
-                    return Json(StatusReturn {
-                        ok: true,
-                        done: true,
-                        position: 0,
-                        error: None,
-                        in_progress: false,
-                    });
-                }
-            }
+#[derive(Serialize)]
+struct JobStatusReturn {
+    ok: bool,
+    job: Option<debug_server::JobInfo>,
+    error: Option<String>,
+}
+
+/// Gets the status of a single job returned by launch_app or attach
+async fn job_status(ip: SecureClientIp, Path(job_id): Path<i64>) -> Json<JobStatusReturn> {
+    let udid = match common::get_udid_from_ip(ip.0.to_string()).await {
+        Ok(u) => u,
+        Err(e) => {
+            return Json(JobStatusReturn {
+                ok: false,
+                job: None,
+                error: Some(e),
+            })
         }
+    };
+
+    match debug_server::get_job(job_id) {
+        // Other devices' jobs look the same as missing ones
+        Some(job) if job.udid == udid => Json(JobStatusReturn {
+            ok: true,
+            job: Some(job),
+            error: None,
+        }),
+        _ => Json(JobStatusReturn {
+            ok: false,
+            job: None,
+            error: Some(format!("No job {job_id}")),
+        }),
+    }
+}
+
+#[derive(Serialize)]
+struct CancelledJob {
+    job_id: i64,
+    /// Whether the app was stopped from launching at all
+    before_launch: bool,
+}
+
+#[derive(Serialize)]
+struct CancelReturn {
+    ok: bool,
+    cancelled: Vec<CancelledJob>,
+    error: Option<String>,
+}
+
+/// Cancels all of the device's unfinished launches
+async fn cancel_launches(
+    ip: SecureClientIp,
+    State(state): State<JitStreamerState>,
+) -> Json<CancelReturn> {
+    let udid = match common::get_udid_from_ip(ip.0.to_string()).await {
+        Ok(u) => u,
+        Err(e) => {
+            return Json(CancelReturn {
+                ok: false,
+                cancelled: Vec::new(),
+                error: Some(e),
+            })
+        }
+    };
+
+    let mut cancelled = Vec::new();
+    for job_id in debug_server::active_jobs(&udid) {
+        if let Some(before_launch) = runner::cancel(&state.cancellations, job_id).await {
+            cancelled.push(CancelledJob {
+                job_id,
+                before_launch,
+            });
+        }
+    }
+    info!("Cancelled {} launches for {udid}", cancelled.len());
+    Json(CancelReturn {
+        ok: true,
+        cancelled,
+        error: None,
+    })
+}
+
+/// Cancels a single launch by its job ID
+async fn cancel_job(
+    ip: SecureClientIp,
+    State(state): State<JitStreamerState>,
+    Path(job_id): Path<i64>,
+) -> Json<CancelReturn> {
+    let udid = match common::get_udid_from_ip(ip.0.to_string()).await {
+        Ok(u) => u,
+        Err(e) => {
+            return Json(CancelReturn {
+                ok: false,
+                cancelled: Vec::new(),
+                error: Some(e),
+            })
+        }
+    };
+
+    match debug_server::get_job(job_id) {
+        Some(job) if job.udid == udid => {}
+        _ => {
+            return Json(CancelReturn {
+                ok: false,
+                cancelled: Vec::new(),
+                error: Some(format!("No job {job_id}")),
+            })
+        }
+    }
+
+    match runner::cancel(&state.cancellations, job_id).await {
+        Some(before_launch) => Json(CancelReturn {
+            ok: true,
+            cancelled: vec![CancelledJob {
+                job_id,
+                before_launch,
+            }],
+            error: None,
+        }),
+        None => Json(CancelReturn {
+            ok: false,
+            cancelled: Vec::new(),
+            error: Some(format!("Job {job_id} has already finished")),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_launch_requests() {
+        let request = serde_json::from_str::<LaunchAppRequest>(
+            r#"{
+                "bundle_id": "com.example.app",
+                "arguments": ["-v", "--mode", "jit"],
+                "environment": {"LOG": "1"},
+                "kill_existing": true,
+                "persistent": true
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(request.bundle_id, "com.example.app");
+        assert_eq!(request.options.arguments, ["-v", "--mode", "jit"]);
+        assert_eq!(request.options.environment["LOG"], "1");
+        assert!(request.options.kill_existing);
+        assert!(request.options.persistent);
+
+        // Everything but the bundle ID is optional
+        let request =
+            serde_json::from_str::<LaunchAppRequest>(r#"{"bundle_id": "com.example.app"}"#)
+                .unwrap();
+        assert!(request.options.arguments.is_empty());
+        assert!(request.options.environment.is_empty());
+        assert!(!request.options.kill_existing);
+        assert!(!request.options.persistent);
+
+        assert!(serde_json::from_str::<LaunchAppRequest>(r#"{"arguments": []}"#).is_err());
+        assert!(serde_json::from_str::<LaunchAppRequest>(
+            r#"{"bundle_id": "com.example.app", "arguments": "-v"}"#
+        )
+        .is_err());
     }
 }
@@ -1,11 +1,15 @@
 // Jackson Coxson
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use axum::{
     extract::{
         ws::{Message, WebSocket},
-        State, WebSocketUpgrade,
+        Query, State, WebSocketUpgrade,
     },
     Json,
 };
@@ -17,27 +21,342 @@ use idevice::{
     IdeviceError, IdeviceService,
 };
 use log::{debug, info, warn};
-use serde::Serialize;
-use tokio::sync::{watch, Mutex};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use tokio::{
+    sync::{oneshot, watch, Mutex, Semaphore, SemaphorePermit},
+    task::AbortHandle,
+};
 
 use crate::{
     common,
-    heartbeat::{self, NewHeartbeatSender},
+    ddi::{DdiImage, LegacyDdi},
+    debug_server, heartbeat,
+    tss::TicketCache,
     JitStreamerState,
 };
 
-const BUILD_MANIFEST: &[u8] = include_bytes!("../DDI/BuildManifest.plist");
-const DDI_IMAGE: &[u8] = include_bytes!("../DDI/Image.dmg");
-const DDI_TRUSTCACHE: &[u8] = include_bytes!("../DDI/Image.dmg.trustcache");
+const PERSONALIZED_IMAGE_TYPE: &str = "DeveloperDiskImage";
+const LEGACY_IMAGE_TYPE: &str = "Developer";
+/// The first iOS version that takes personalized images
+const PERSONALIZED_MAJOR_VERSION: u32 = 17;
+
+pub type MountCache = Arc<Mutex<HashMap<String, MountEntry>>>;
+type ProgressSender = watch::Sender<Result<(usize, usize, bool), String>>;
+
+/// How long a finished mount is kept for the client to come back for
+const MOUNT_RESULT_TTL: Duration = Duration::from_secs(10 * 60);
+/// How long a mount can go without progress before it's given up on
+const MOUNT_STALL_TIMEOUT: Duration = Duration::from_secs(2 * 60);
 
-pub type MountCache =
-    Arc<Mutex<HashMap<String, watch::Receiver<Result<(usize, usize, bool), String>>>>>;
+/// A mount that's queued, running or waiting for the client to see how it went
+pub struct MountEntry {
+    progress: ProgressSender,
+    /// Only used to notice progress, so clients' receivers are left alone
+    watcher: watch::Receiver<Result<(usize, usize, bool), String>>,
+    image: String,
+    started_at: String,
+    /// When the mount last made progress or finished
+    last_change: Instant,
+    task: AbortHandle,
+}
+
+impl MountEntry {
+    fn finished(&self) -> bool {
+        !matches!(*self.progress.borrow(), Ok((_, _, false)))
+    }
+
+    /// Stops the mount, leaving the error for the client
+    fn abort(&mut self, udid: &str, queue: &MountQueue, error: &str) {
+        self.task.abort();
+        queue.leave(udid);
+        self.progress.send(Err(error.to_string())).ok();
+        self.last_change = Instant::now();
+    }
+
+    fn info(&self, udid: &str, queue: &MountQueue) -> MountInfo {
+        let (progress, error) = match &*self.progress.borrow() {
+            Ok((done, total, _)) => (Some((*done, *total)), None),
+            Err(e) => (None, Some(e.clone())),
+        };
+        MountInfo {
+            udid: udid.to_string(),
+            image: self.image.clone(),
+            started_at: self.started_at.clone(),
+            finished: self.finished(),
+            progress,
+            error,
+            queue_position: queue.position(udid),
+            idle_secs: self.last_change.elapsed().as_secs(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct MountInfo {
+    udid: String,
+    image: String,
+    started_at: String,
+    finished: bool,
+    /// Bytes uploaded and the image's size
+    progress: Option<(usize, usize)>,
+    error: Option<String>,
+    queue_position: Option<usize>,
+    /// Seconds since the mount last made progress or finished
+    idle_secs: u64,
+}
+
+/// Every mount the server is keeping track of
+pub async fn list(state: &JitStreamerState) -> Vec<MountInfo> {
+    let mut mounts = state
+        .mount_cache
+        .lock()
+        .await
+        .iter()
+        .map(|(udid, e)| e.info(udid, &state.mount_queue))
+        .collect::<Vec<_>>();
+    mounts.sort_by(|a, b| a.udid.cmp(&b.udid));
+    mounts
+}
+
+/// Stops the device's mount and forgets it, returning whether there was one
+pub async fn forget(state: &JitStreamerState, udid: &str) -> bool {
+    match state.mount_cache.lock().await.remove(udid) {
+        Some(mut e) => {
+            e.abort(udid, &state.mount_queue, "the mount was cancelled");
+            true
+        }
+        None => false,
+    }
+}
+
+/// Forgets finished mounts nobody came back for, and gives up on ones that stopped moving
+pub async fn maintain(cache: MountCache, queue: Arc<MountQueue>) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(15)).await;
+        cache.lock().await.retain(|udid, e| {
+            // Waiting in line isn't stalling
+            if e.watcher.has_changed().unwrap_or(false) || queue.position(udid).is_some() {
+                e.watcher.borrow_and_update();
+                e.last_change = Instant::now();
+            }
+            let idle = e.last_change.elapsed();
+            if e.finished() {
+                if idle > MOUNT_RESULT_TTL {
+                    debug!("Forgetting the mount on {udid}");
+                    return false;
+                }
+            } else if idle > MOUNT_STALL_TIMEOUT {
+                warn!("The mount on {udid} stalled, giving up on it");
+                e.abort(udid, &queue, "the mount stopped making progress");
+            }
+            true
+        });
+    }
+}
+
+const DEFAULT_MOUNT_CONCURRENCY: usize = 2;
+
+/// Devices waiting to mount, so only MOUNT_CONCURRENCY images are uploaded at once
+pub struct MountQueue {
+    permits: Semaphore,
+    /// First in line first
+    waiting: std::sync::Mutex<VecDeque<String>>,
+    /// Sent whenever the line moves
+    moved: watch::Sender<()>,
+}
+
+impl MountQueue {
+    pub fn from_env() -> Self {
+        let concurrency = std::env::var("MOUNT_CONCURRENCY")
+            .ok()
+            .and_then(|c| c.parse::<usize>().ok())
+            .filter(|c| *c > 0)
+            .unwrap_or(DEFAULT_MOUNT_CONCURRENCY);
+        info!("Mounting on up to {concurrency} devices at once");
+        Self::new(concurrency)
+    }
+
+    fn new(concurrency: usize) -> Self {
+        Self {
+            permits: Semaphore::new(concurrency),
+            waiting: std::sync::Mutex::new(VecDeque::new()),
+            moved: watch::channel(()).0,
+        }
+    }
+
+    /// Takes the device out of line if it's waiting
+    fn leave(&self, udid: &str) {
+        self.waiting.lock().unwrap().retain(|u| u != udid);
+        self.moved.send_replace(());
+    }
+
+    /// Puts the device at the back of the line, returning its place starting from 1
+    fn join(&self, udid: &str) -> usize {
+        let mut waiting = self.waiting.lock().unwrap();
+        waiting.push_back(udid.to_string());
+        waiting.len()
+    }
+
+    /// Waits for the device's turn to mount, which lasts until the permit is dropped.
+    /// The semaphore is fair, so devices are let through in the order they asked.
+    async fn turn(&self, udid: &str) -> SemaphorePermit<'_> {
+        let permit = self.permits.acquire().await.unwrap();
+        self.leave(udid);
+        permit
+    }
+
+    /// The device's place in line, or None if it isn't waiting
+    pub fn position(&self, udid: &str) -> Option<usize> {
+        self.waiting
+            .lock()
+            .unwrap()
+            .iter()
+            .position(|u| u == udid)
+            .map(|p| p + 1)
+    }
+}
 
 #[derive(Serialize)]
 pub struct CheckMountResponse {
     ok: bool,
     error: Option<String>,
     mounting: bool,
+    /// What the device has mounted, when we asked it
+    images: Option<Vec<MountedImage>>,
+    ddi: Option<DdiStatus>,
+    /// The image the server would mount for the device's iOS version
+    ddi_image: Option<DdiChoice>,
+    /// The device's place in line while it waits for other mounts to finish
+    queue_position: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct CheckMountQuery {
+    /// Replace a developer image that isn't ours
+    #[serde(default)]
+    remount: bool,
+    /// Start over, giving up on the mount that's running
+    #[serde(default)]
+    retry: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageKind {
+    /// Signed for the device through TSS, iOS 17 and up
+    Personalized,
+    /// Signed by Apple for a whole iOS version
+    Legacy,
+}
+
+/// An entry from the mounter's list of mounted images
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MountedImage {
+    pub kind: ImageKind,
+    /// DeveloperDiskImage, Cryptex and so on for personalized images, Developer for legacy
+    pub image_type: Option<String>,
+    pub mount_path: Option<String>,
+    /// SHA-256 of the image's signature, to tell images apart
+    pub signature: Option<String>,
+}
+
+impl MountedImage {
+    fn from_plist(image: &plist::Value) -> Option<Self> {
+        let image = image.as_dictionary()?;
+        let string = |key: &str| image.get(key).and_then(|v| v.as_string()).map(String::from);
+
+        let personalized = string("DiskImageType").as_deref() == Some("Personalized")
+            || image.contains_key("PersonalizedImageType");
+        let (kind, image_type) = if personalized {
+            (ImageKind::Personalized, string("PersonalizedImageType"))
+        } else {
+            (ImageKind::Legacy, string("ImageType"))
+        };
+
+        Some(Self {
+            kind,
+            image_type,
+            mount_path: string("MountPath"),
+            signature: image
+                .get("ImageSignature")
+                .and_then(|v| v.as_data())
+                .map(signature_hash),
+        })
+    }
+
+    fn is_developer(&self) -> bool {
+        match self.kind {
+            ImageKind::Personalized => {
+                self.image_type.as_deref() == Some(PERSONALIZED_IMAGE_TYPE)
+                    || self.mount_path.as_deref() == Some("/System/Developer")
+            }
+            ImageKind::Legacy => {
+                self.image_type.as_deref() == Some(LEGACY_IMAGE_TYPE)
+                    || self.mount_path.as_deref() == Some("/Developer")
+            }
+        }
+    }
+}
+
+/// Whether the developer image on the device is the one we'd mount
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DdiStatus {
+    Current,
+    /// A personalized developer image, but not ours
+    Outdated,
+    /// A legacy developer image on a device that takes personalized images
+    Legacy,
+    NotMounted,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DdiChoice {
+    device_version: String,
+    kind: ImageKind,
+    /// The personalized image's name, or the legacy image's iOS version
+    image: String,
+}
+
+/// The image picked for a device
+enum DdiSource {
+    Personalized(Arc<DdiImage>),
+    Legacy(Arc<LegacyDdi>),
+}
+
+impl DdiSource {
+    fn name(&self) -> String {
+        match self {
+            DdiSource::Personalized(i) => i.name.clone(),
+            DdiSource::Legacy(i) => i.version.clone(),
+        }
+    }
+
+    fn choice(&self, device_version: &str) -> DdiChoice {
+        DdiChoice {
+            device_version: device_version.to_string(),
+            kind: match self {
+                DdiSource::Personalized(_) => ImageKind::Personalized,
+                DdiSource::Legacy(_) => ImageKind::Legacy,
+            },
+            image: self.name(),
+        }
+    }
+}
+
+/// Whether the device's iOS version mounts personalized images.
+/// Versions we can't read are assumed to be new.
+fn takes_personalized(product_version: &str) -> bool {
+    product_version
+        .split('.')
+        .next()
+        .and_then(|m| m.parse::<u32>().ok())
+        .is_none_or(|m| m >= PERSONALIZED_MAJOR_VERSION)
+}
+
+fn signature_hash(signature: &[u8]) -> String {
+    format!("{:x}", sha2::Sha256::digest(signature))
 }
 
 #[derive(Serialize)]
@@ -46,11 +365,13 @@ pub struct MountWebSocketMessage {
     percentage: f32,
     error: Option<String>,
     done: bool,
+    queue_position: Option<usize>,
 }
 
 pub async fn check_mount(
     ip: SecureClientIp,
     State(state): State<JitStreamerState>,
+    Query(query): Query<CheckMountQuery>,
 ) -> Json<CheckMountResponse> {
     let udid = match common::get_udid_from_ip(ip.0.to_string()).await {
         Ok(u) => u,
@@ -59,13 +380,23 @@ pub async fn check_mount(
                 ok: false,
                 error: Some(e),
                 mounting: false,
+                images: None,
+                ddi: None,
+                ddi_image: None,
+                queue_position: None,
             });
         }
     };
 
     let mut lock = state.mount_cache.lock().await;
+    if query.retry {
+        if let Some(mut e) = lock.remove(&udid) {
+            info!("Retrying the mount on {udid}");
+            e.abort(&udid, &state.mount_queue, "the mount was retried");
+        }
+    }
     if let Some(i) = lock.get(&udid) {
-        let i = i.borrow().clone();
+        let i = i.progress.borrow().clone();
         match i {
             Ok((_, _, complete)) => {
                 if complete {
@@ -74,6 +405,10 @@ pub async fn check_mount(
                         ok: true,
                         error: None,
                         mounting: false,
+                        images: None,
+                        ddi: None,
+                        ddi_image: None,
+                        queue_position: None,
                     });
                 }
             }
@@ -83,6 +418,10 @@ pub async fn check_mount(
                     ok: false,
                     error: Some(format!("Failed to mount image: {e}")),
                     mounting: false,
+                    images: None,
+                    ddi: None,
+                    ddi_image: None,
+                    queue_position: None,
                 });
             }
         }
@@ -91,6 +430,10 @@ pub async fn check_mount(
             ok: true,
             error: None,
             mounting: true,
+            images: None,
+            ddi: None,
+            ddi_image: None,
+            queue_position: state.mount_queue.position(&udid),
         });
     }
     std::mem::drop(lock);
@@ -102,19 +445,18 @@ pub async fn check_mount(
                 ok: false,
                 mounting: false,
                 error: Some(format!("Unable to get pairing file: {e}")),
+                images: None,
+                ddi: None,
+                ddi_image: None,
+                queue_position: None,
             })
         }
     };
 
-    // Start a heartbeat, get the list of images
-    match heartbeat::heartbeat_thread(udid.clone(), ip.0, &pairing_file).await {
-        Ok(s) => {
-            state
-                .new_heartbeat_sender
-                .send(heartbeat::SendRequest::Store((udid.clone(), s)))
-                .await
-                .unwrap();
-        }
+    // Start a heartbeat, get the list of images.
+    // It stops when this returns, or when the mount thread it's handed to ends however it ends.
+    let heartbeat = match heartbeat::heartbeat_thread(udid.clone(), ip.0, &pairing_file).await {
+        Ok(s) => s,
         Err(e) => {
             let e = match e {
                 idevice::IdeviceError::InvalidHostID => {
@@ -127,9 +469,13 @@ pub async fn check_mount(
                 ok: false,
                 mounting: false,
                 error: Some(format!("Failed to heartbeat device: {e}")),
+                images: None,
+                ddi: None,
+                ddi_image: None,
+                queue_position: None,
             });
         }
-    }
+    };
 
     // Get the list of mounted images
     let provider = TcpProvider {
@@ -145,6 +491,10 @@ pub async fn check_mount(
                 ok: false,
                 mounting: false,
                 error: Some(format!("Failed to start image mounter: {e:?}")),
+                images: None,
+                ddi: None,
+                ddi_image: None,
+                queue_position: None,
             })
         }
     };
@@ -157,106 +507,364 @@ pub async fn check_mount(
                 ok: false,
                 mounting: false,
                 error: Some(format!("Failed to get images: {:?}", e)),
+                images: None,
+                ddi: None,
+                ddi_image: None,
+                queue_position: None,
             });
         }
     };
 
-    let mut mounted = false;
-    for image in images {
-        let mut buf = Vec::new();
-        let mut writer = std::io::Cursor::new(&mut buf);
-        plist::to_writer_xml(&mut writer, &image).unwrap();
+    let images = images
+        .iter()
+        .filter_map(MountedImage::from_plist)
+        .collect::<Vec<_>>();
+    let identity = match DeviceIdentity::get(&provider).await {
+        Ok(i) => i,
+        Err(e) => {
+            return Json(CheckMountResponse {
+                ok: false,
+                mounting: false,
+                error: Some(format!("Failed to get device info: {e}")),
+                images: Some(images),
+                ddi: None,
+                ddi_image: None,
+                queue_position: None,
+            })
+        }
+    };
+    let source = if takes_personalized(&identity.product_version) {
+        state
+            .ddi
+            .pick(identity.chip_id, identity.board_id)
+            .map(DdiSource::Personalized)
+    } else {
+        state
+            .ddi
+            .pick_legacy(&identity.product_version)
+            .map(DdiSource::Legacy)
+    };
+    let ddi_image = source.as_ref().map(|s| s.choice(&identity.product_version));
 
-        let image = String::from_utf8_lossy(&buf);
-        if image.contains("Developer") {
-            mounted = true;
-            break;
+    let developer = images.iter().find(|i| i.is_developer());
+    let ddi = match (developer, &source) {
+        (None, _) => DdiStatus::NotMounted,
+        (Some(i), Some(DdiSource::Legacy(legacy))) => {
+            if i.signature.as_deref() == Some(signature_hash(&legacy.signature).as_str()) {
+                DdiStatus::Current
+            } else {
+                DdiStatus::Outdated
+            }
         }
-    }
+        (Some(i), Some(DdiSource::Personalized(_))) if i.kind == ImageKind::Legacy => {
+            DdiStatus::Legacy
+        }
+        (Some(_), None) => DdiStatus::Outdated,
+        (Some(_), Some(DdiSource::Personalized(ddi_image))) => {
+            // The device only has a manifest for our image if it's what's mounted
+            match mounter_client
+                .query_personalization_manifest(PERSONALIZED_IMAGE_TYPE, ddi_image.hash.clone())
+                .await
+            {
+                Ok(_) => DdiStatus::Current,
+                Err(e) => {
+                    debug!("No manifest for our image on {udid}: {e:?}");
+                    DdiStatus::Outdated
+                }
+            }
+        }
+    };
+    info!(
+        "Developer image on {udid} (iOS {}): {ddi:?}",
+        identity.product_version
+    );
+
+    let unmount = match ddi {
+        DdiStatus::Current => None,
+        DdiStatus::NotMounted => Some(None),
+        _ if query.remount => Some(developer.and_then(|i| i.mount_path.clone())),
+        // It still works for now, the client can ask us to replace it
+        _ => None,
+    };
 
-    if mounted {
-        Json(CheckMountResponse {
+    match (unmount, source) {
+        (None, _) => Json(CheckMountResponse {
             ok: true,
             error: None,
             mounting: false,
-        })
-    } else {
-        let (sw, rw) = watch::channel(Ok((0, 100, false)));
-        mount_thread(
-            provider,
-            sw,
-            state.new_heartbeat_sender.clone(),
-            udid.clone(),
-        );
-        state.mount_cache.lock().await.insert(udid, rw);
+            images: Some(images),
+            ddi: Some(ddi),
+            ddi_image,
+            queue_position: None,
+        }),
+        (Some(_), None) => Json(CheckMountResponse {
+            ok: false,
+            error: Some(format!(
+                "The server has no developer disk image for this device (iOS {}, chip {:#x}, board {:#x})",
+                identity.product_version, identity.chip_id, identity.board_id
+            )),
+            mounting: false,
+            images: Some(images),
+            ddi: Some(ddi),
+            ddi_image,
+            queue_position: None,
+        }),
+        (Some(unmount), Some(source)) => {
+            let image = source.name();
+            let started = start_mount(
+                &mut *state.mount_cache.lock().await,
+                &state.mount_queue,
+                &udid,
+                || {
+                    info!("Mounting developer disk image {image} on {udid}");
+                    let (sw, rw) = watch::channel(Ok((0, 100, false)));
+                    let task = mount_thread(
+                        provider,
+                        sw.clone(),
+                        heartbeat,
+                        udid.clone(),
+                        MountRequest {
+                            image: source,
+                            unique_chip_id: identity.unique_chip_id,
+                            unmount,
+                            tickets: state.tickets.clone(),
+                            queue: state.mount_queue.clone(),
+                        },
+                    );
+                    MountEntry {
+                        progress: sw,
+                        watcher: rw,
+                        image: image.clone(),
+                        started_at: debug_server::timestamp(),
+                        last_change: Instant::now(),
+                        task,
+                    }
+                },
+            );
+            let queue_position = match started {
+                Some(position) => Some(position),
+                None => {
+                    debug!("Another request started the mount on {udid} first");
+                    state.mount_queue.position(&udid)
+                }
+            };
 
-        Json(CheckMountResponse {
-            ok: true,
-            error: None,
-            mounting: true,
+            Json(CheckMountResponse {
+                ok: true,
+                error: None,
+                mounting: true,
+                images: Some(images),
+                ddi: Some(ddi),
+                ddi_image,
+                queue_position,
+            })
+        }
+    }
+}
+
+/// Puts the device in line and starts its mount, unless a request that got here
+/// while the cache was unlocked already did. Returns its place in line if it started.
+fn start_mount(
+    cache: &mut HashMap<String, MountEntry>,
+    queue: &MountQueue,
+    udid: &str,
+    start: impl FnOnce() -> MountEntry,
+) -> Option<usize> {
+    if cache.get(udid).is_some_and(|e| !e.finished()) {
+        return None;
+    }
+    // In line before the thread starts, which leaves the line when it's its turn
+    let position = queue.join(udid);
+    if let Some(old) = cache.insert(udid.to_string(), start()) {
+        old.task.abort();
+    }
+    Some(position)
+}
+
+/// The IDs that decide which image the device takes
+struct DeviceIdentity {
+    chip_id: u64,
+    board_id: u64,
+    unique_chip_id: u64,
+    product_version: String,
+}
+
+impl DeviceIdentity {
+    async fn get(provider: &TcpProvider) -> Result<Self, IdeviceError> {
+        let mut lockdown_client = LockdowndClient::connect(provider).await?;
+        lockdown_client
+            .start_session(&provider.get_pairing_file().await?)
+            .await?;
+        Ok(Self {
+            chip_id: unsigned_value(&mut lockdown_client, "ChipID").await?,
+            board_id: unsigned_value(&mut lockdown_client, "BoardId").await?,
+            unique_chip_id: unsigned_value(&mut lockdown_client, "UniqueChipID").await?,
+            product_version: lockdown_client
+                .get_value("ProductVersion")
+                .await?
+                .as_string()
+                .ok_or(IdeviceError::UnexpectedResponse)?
+                .to_string(),
         })
     }
 }
 
+async fn unsigned_value(client: &mut LockdowndClient, key: &str) -> Result<u64, IdeviceError> {
+    client
+        .get_value(key)
+        .await?
+        .as_unsigned_integer()
+        .ok_or(IdeviceError::UnexpectedResponse)
+}
+
+struct MountRequest {
+    image: DdiSource,
+    unique_chip_id: u64,
+    /// Where the developer image being replaced is mounted
+    unmount: Option<String>,
+    tickets: Arc<TicketCache>,
+    queue: Arc<MountQueue>,
+}
+
 fn mount_thread(
     provider: TcpProvider,
-    sender: watch::Sender<Result<(usize, usize, bool), String>>,
-    hb: NewHeartbeatSender,
+    sender: ProgressSender,
+    heartbeat: oneshot::Sender<()>,
     udid: String,
-) {
+    request: MountRequest,
+) -> AbortHandle {
     debug!("Starting mount thread for {udid}");
     tokio::task::spawn(async move {
+        // The device's heartbeat stops when this is dropped, however the mount ends
+        let _heartbeat = heartbeat;
         // Start work in a new fuction so we can use ?
         async fn work(
             provider: TcpProvider,
-            sender: watch::Sender<Result<(usize, usize, bool), String>>,
-            hb: NewHeartbeatSender,
+            sender: ProgressSender,
             udid: String,
+            request: MountRequest,
         ) -> Result<(), IdeviceError> {
-            debug!("Getting chip ID for {udid}");
-            let mut lockdown_client = LockdowndClient::connect(&provider).await?;
-            lockdown_client
-                .start_session(&provider.get_pairing_file().await?)
-                .await?;
-
-            let unique_chip_id = match lockdown_client
-                .get_value("UniqueChipID")
-                .await?
-                .as_unsigned_integer()
-            {
-                Some(u) => u,
-                None => {
-                    return Err(IdeviceError::UnexpectedResponse);
-                }
-            };
-
+            let queue = request.queue.clone();
+            if let Some(position) = queue.position(&udid) {
+                debug!("{udid} is number {position} in line to mount");
+            }
+            let _permit = queue.turn(&udid).await;
             let mut mounter_client = ImageMounter::connect(&provider).await?;
-            mounter_client
-                .mount_personalized_with_callback(
-                    &provider,
-                    DDI_IMAGE.to_vec(),
-                    DDI_TRUSTCACHE.to_vec(),
-                    BUILD_MANIFEST,
-                    None,
-                    unique_chip_id,
-                    |(progress, state)| async move {
-                        state.clone().send(Ok((progress.0, progress.1, false))).ok();
-                    },
-                    sender,
-                )
-                .await?;
-            hb.send(crate::heartbeat::SendRequest::Kill(udid))
-                .await
-                .ok();
+            if let Some(path) = request.unmount {
+                info!("Unmounting the developer image at {path} on {udid}");
+                mounter_client.unmount_image(path).await?;
+            }
+            match request.image {
+                DdiSource::Personalized(image) => {
+                    mount_personalized(
+                        &provider,
+                        mounter_client,
+                        &image,
+                        request.unique_chip_id,
+                        &request.tickets,
+                        sender,
+                    )
+                    .await?;
+                }
+                DdiSource::Legacy(image) => {
+                    mounter_client
+                        .upload_image_with_progress(
+                            LEGACY_IMAGE_TYPE,
+                            &image.image,
+                            image.signature.to_vec(),
+                            report_progress,
+                            sender,
+                        )
+                        .await?;
+                    mounter_client
+                        .mount_image(
+                            LEGACY_IMAGE_TYPE,
+                            image.signature.to_vec(),
+                            Vec::new(),
+                            plist::Value::Dictionary(plist::Dictionary::new()),
+                        )
+                        .await?;
+                }
+            }
             Ok(())
         }
-        if let Err(e) = work(provider, sender.clone(), hb, udid.clone()).await {
+        if let Err(e) = work(provider, sender.clone(), udid.clone(), request).await {
             warn!("Failed to mount for {udid}: {e:?}");
             sender.send(Err(e.to_string())).ok();
         } else {
             sender.send(Ok((1, 1, true))).ok();
         }
-    });
+    })
+    .abort_handle()
+}
+
+/// Mounts a personalized image, using the cached TSS ticket for the device when there is one.
+/// If the device turns the cached ticket down, TSS is asked for a new one.
+async fn mount_personalized(
+    provider: &TcpProvider,
+    mut mounter_client: ImageMounter,
+    image: &DdiImage,
+    ecid: u64,
+    tickets: &TicketCache,
+    sender: ProgressSender,
+) -> Result<(), IdeviceError> {
+    // The device keeps the ticket for an image it's mounted since it booted
+    if let Ok(ticket) = mounter_client
+        .query_personalization_manifest(PERSONALIZED_IMAGE_TYPE, image.hash.clone())
+        .await
+    {
+        debug!("Device {ecid:#x} already has a ticket for {}", image.name);
+        return upload_personalized(&mut mounter_client, image, ticket, sender).await;
+    }
+
+    if let Some(ticket) = tickets.get(ecid, &image.manifest) {
+        match upload_personalized(&mut mounter_client, image, ticket, sender.clone()).await {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                warn!("Device {ecid:#x} didn't take its cached ticket: {e:?}");
+                tickets.reject(ecid, &image.manifest);
+                // Start over on a new connection, the failed mount may have closed this one
+                mounter_client = ImageMounter::connect(provider).await?;
+            }
+        }
+    }
+
+    let build_manifest: plist::Dictionary =
+        plist::from_bytes(&image.manifest).map_err(|_| IdeviceError::UnexpectedResponse)?;
+    let ticket = mounter_client
+        .get_manifest_from_tss(&build_manifest, ecid)
+        .await?;
+    tickets.insert(ecid, &image.manifest, ticket.clone());
+    upload_personalized(&mut mounter_client, image, ticket, sender).await
+}
+
+async fn upload_personalized(
+    mounter_client: &mut ImageMounter,
+    image: &DdiImage,
+    ticket: Vec<u8>,
+    sender: ProgressSender,
+) -> Result<(), IdeviceError> {
+    mounter_client
+        .upload_image_with_progress(
+            "Personalized",
+            &image.image,
+            ticket.clone(),
+            report_progress,
+            sender,
+        )
+        .await?;
+    mounter_client
+        .mount_image(
+            "Personalized",
+            ticket,
+            image.trustcache.to_vec(),
+            plist::Value::Dictionary(plist::Dictionary::new()),
+        )
+        .await
+}
+
+async fn report_progress(((done, total), sender): ((usize, usize), ProgressSender)) {
+    sender.send(Ok((done, total, false))).ok();
 }
 
 pub async fn handler(
@@ -279,6 +887,7 @@ async fn handle_socket(mut socket: WebSocket, ip: String, state: JitStreamerStat
                         percentage: 0.0,
                         error: Some(e),
                         done: false,
+                        queue_position: None,
                     }
                     .to_ws_message(),
                 )
@@ -290,7 +899,7 @@ async fn handle_socket(mut socket: WebSocket, ip: String, state: JitStreamerStat
 
     let lock = state.mount_cache.lock().await;
     let mut receiver = match lock.get(&udid) {
-        Some(r) => r.clone(),
+        Some(e) => e.progress.subscribe(),
         None => {
             socket
                 .send(
@@ -299,6 +908,7 @@ async fn handle_socket(mut socket: WebSocket, ip: String, state: JitStreamerStat
                         error: None,
                         percentage: 0.0,
                         done: false,
+                        queue_position: None,
                     }
                     .to_ws_message(),
                 )
@@ -309,8 +919,11 @@ async fn handle_socket(mut socket: WebSocket, ip: String, state: JitStreamerStat
     };
     std::mem::drop(lock);
 
+    let mut moved = state.mount_queue.moved.subscribe();
     loop {
-        let msg = receiver.borrow().clone();
+        let msg = receiver.borrow_and_update().clone();
+        // The entry keeps the channel open, so stop once the mount is over
+        let finished = !matches!(msg, Ok((_, _, false)));
         if match msg {
             Ok((a, b, complete)) => socket.send(
                 MountWebSocketMessage {
@@ -318,6 +931,7 @@ async fn handle_socket(mut socket: WebSocket, ip: String, state: JitStreamerStat
                     error: None,
                     percentage: a as f32 / b as f32,
                     done: complete,
+                    queue_position: state.mount_queue.position(&udid),
                 }
                 .to_ws_message(),
             ),
@@ -327,6 +941,7 @@ async fn handle_socket(mut socket: WebSocket, ip: String, state: JitStreamerStat
                     error: Some(e),
                     percentage: 0.0,
                     done: false,
+                    queue_position: None,
                 }
                 .to_ws_message(),
             ),
@@ -337,11 +952,20 @@ async fn handle_socket(mut socket: WebSocket, ip: String, state: JitStreamerStat
             debug!("Failed to send status to websocket");
             return;
         }
-
-        if receiver.changed().await.is_err() {
-            debug!("Receiver failed to recv msg");
+        if finished {
             return;
         }
+
+        // Progress, or the line moving while the device waits its turn
+        tokio::select! {
+            res = receiver.changed() => {
+                if res.is_err() {
+                    debug!("Receiver failed to recv msg");
+                    return;
+                }
+            }
+            _ = moved.changed() => {}
+        }
     }
 }
 
@@ -350,3 +974,157 @@ impl MountWebSocketMessage {
         Message::text(serde_json::to_string(&self).unwrap())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn image(entries: &[(&str, plist::Value)]) -> plist::Value {
+        plist::Value::Dictionary(
+            entries
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn parses_mounted_images() {
+        let personalized = MountedImage::from_plist(&image(&[
+            ("DiskImageType", "Personalized".into()),
+            ("PersonalizedImageType", "DeveloperDiskImage".into()),
+            ("MountPath", "/System/Developer".into()),
+            ("ImageSignature", plist::Value::Data(vec![1, 2, 3])),
+        ]))
+        .unwrap();
+        assert_eq!(personalized.kind, ImageKind::Personalized);
+        assert_eq!(
+            personalized.image_type.as_deref(),
+            Some("DeveloperDiskImage")
+        );
+        assert_eq!(
+            personalized.signature,
+            Some(format!("{:x}", sha2::Sha256::digest([1, 2, 3])))
+        );
+        assert!(personalized.is_developer());
+
+        let legacy = MountedImage::from_plist(&image(&[
+            ("ImageType", "Developer".into()),
+            ("MountPath", "/Developer".into()),
+        ]))
+        .unwrap();
+        assert_eq!(legacy.kind, ImageKind::Legacy);
+        assert_eq!(legacy.signature, None);
+        assert!(legacy.is_developer());
+
+        // Mentioning Developer somewhere doesn't make it a developer image
+        let cryptex = MountedImage::from_plist(&image(&[
+            ("DiskImageType", "Personalized".into()),
+            ("PersonalizedImageType", "Cryptex".into()),
+            ("MountPath", "/private/preboot/Cryptexes/Developer".into()),
+        ]))
+        .unwrap();
+        assert!(!cryptex.is_developer());
+
+        assert_eq!(MountedImage::from_plist(&"Developer".into()), None);
+    }
+
+    #[tokio::test]
+    async fn queues_mounts() {
+        let queue = MountQueue::new(1);
+        assert_eq!(queue.join("a"), 1);
+        assert_eq!(queue.join("b"), 2);
+        assert_eq!(queue.position("b"), Some(2));
+
+        let first = queue.turn("a").await;
+        assert_eq!(queue.position("a"), None);
+        assert_eq!(queue.position("b"), Some(1));
+
+        // b waits for a to finish
+        let waiting = tokio::time::timeout(Duration::from_millis(50), queue.turn("b")).await;
+        assert!(waiting.is_err());
+        drop(first);
+        let _second = queue.turn("b").await;
+        assert_eq!(queue.position("b"), None);
+    }
+
+    #[tokio::test]
+    async fn aborts_mounts() {
+        let queue = MountQueue::new(1);
+        queue.join("a");
+        let (progress, watcher) = watch::channel(Ok((0, 100, false)));
+        // Like the mount thread, the task holds the device's heartbeat
+        let (heartbeat, stopped) = oneshot::channel::<()>();
+        let mut entry = MountEntry {
+            progress,
+            watcher,
+            image: "17.4".into(),
+            started_at: String::new(),
+            last_change: Instant::now(),
+            task: tokio::task::spawn(async move {
+                let _heartbeat = heartbeat;
+                std::future::pending::<()>().await
+            })
+            .abort_handle(),
+        };
+        assert!(!entry.finished());
+
+        entry.abort("a", &queue, "the mount stopped making progress");
+        assert!(entry.finished());
+        let info = entry.info("a", &queue);
+        assert_eq!(info.queue_position, None);
+        assert_eq!(
+            info.error.as_deref(),
+            Some("the mount stopped making progress")
+        );
+        // So the heartbeat stops with it
+        assert!(stopped.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn starts_one_mount_per_device() {
+        let queue = MountQueue::new(1);
+        let mut cache = HashMap::new();
+        let entry = || {
+            let (progress, watcher) = watch::channel(Ok((0, 100, false)));
+            MountEntry {
+                progress,
+                watcher,
+                image: "17.4".into(),
+                started_at: String::new(),
+                last_change: Instant::now(),
+                task: tokio::task::spawn(std::future::pending::<()>()).abort_handle(),
+            }
+        };
+
+        assert_eq!(start_mount(&mut cache, &queue, "a", entry), Some(1));
+        // Two requests that both looked before either started
+        assert_eq!(
+            start_mount(&mut cache, &queue, "a", || panic!("started twice")),
+            None
+        );
+        assert_eq!(start_mount(&mut cache, &queue, "b", entry), Some(2));
+        assert_eq!(queue.position("b"), Some(2));
+
+        // A finished mount can be started over
+        cache
+            .get("a")
+            .unwrap()
+            .progress
+            .send(Err("failed".into()))
+            .unwrap();
+        queue.leave("a");
+        assert_eq!(start_mount(&mut cache, &queue, "a", entry), Some(2));
+    }
+
+    #[test]
+    fn splits_versions() {
+        assert!(takes_personalized("17.0"));
+        assert!(takes_personalized("18.4.1"));
+        assert!(!takes_personalized("16.7.10"));
+        assert!(!takes_personalized("15.8"));
+        assert!(takes_personalized("unknown"));
+    }
+}
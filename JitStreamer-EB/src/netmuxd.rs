@@ -0,0 +1,697 @@
+// Jackson Coxson
+// A usbmuxd client over netmuxd's socket, with netmuxd's extensions for adding
+// and removing network devices
+
+use std::{
+    collections::VecDeque,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use log::{debug, error};
+use plist::{Dictionary, Value};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+};
+
+const NETMUXD_SOCKET: &str = "/var/run/usbmuxd";
+const SERVICE_NAME: &str = "apple-mobdev2";
+const SERVICE_PROTOCOL: &str = "tcp";
+const PROG_NAME: &str = "JitStreamer-EB";
+
+/// Plist packets are version 1, message type 8
+const PLIST_VERSION: u32 = 1;
+const PLIST_MESSAGE: u32 = 8;
+const HEADER_SIZE: usize = 16;
+/// Nothing usbmuxd sends comes close, anything bigger means the stream is garbage
+const MAX_PACKET_SIZE: usize = 1024 * 1024;
+
+const RECENT_EVENTS_LEN: usize = 50;
+const WATCH_RETRY_DELAY: Duration = Duration::from_secs(5);
+static RECENT_EVENTS: LazyLock<Mutex<VecDeque<RecentEvent>>> =
+    LazyLock::new(|| Mutex::new(VecDeque::with_capacity(RECENT_EVENTS_LEN)));
+
+#[derive(Debug)]
+pub enum UsbmuxdError {
+    Io(std::io::Error),
+    Plist(plist::Error),
+    BadPacket(&'static str),
+    UnexpectedResponse(String),
+    /// usbmuxd answered with a non-zero result code
+    Refused(u64),
+}
+
+impl std::fmt::Display for UsbmuxdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UsbmuxdError::Io(e) => write!(f, "usbmuxd connection error: {e}"),
+            UsbmuxdError::Plist(e) => write!(f, "bad plist from usbmuxd: {e}"),
+            UsbmuxdError::BadPacket(r) => write!(f, "bad packet from usbmuxd: {r}"),
+            UsbmuxdError::UnexpectedResponse(r) => write!(f, "unexpected usbmuxd response: {r}"),
+            UsbmuxdError::Refused(n) => write!(f, "usbmuxd refused the request with {n}"),
+        }
+    }
+}
+
+impl From<std::io::Error> for UsbmuxdError {
+    fn from(value: std::io::Error) -> Self {
+        UsbmuxdError::Io(value)
+    }
+}
+
+impl From<plist::Error> for UsbmuxdError {
+    fn from(value: plist::Error) -> Self {
+        UsbmuxdError::Plist(value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct RawPacket {
+    plist: Dictionary,
+    version: u32,
+    message: u32,
+    tag: u32,
+}
+
+impl RawPacket {
+    fn new(plist: Dictionary, tag: u32) -> Self {
+        Self {
+            plist,
+            version: PLIST_VERSION,
+            message: PLIST_MESSAGE,
+            tag,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut plist_bytes = Vec::new();
+        plist::to_writer_xml(&mut plist_bytes, &self.plist).unwrap();
+
+        let size = (HEADER_SIZE + plist_bytes.len()) as u32;
+        let mut packet = Vec::with_capacity(size as usize);
+        packet.extend_from_slice(&size.to_le_bytes());
+        packet.extend_from_slice(&self.version.to_le_bytes());
+        packet.extend_from_slice(&self.message.to_le_bytes());
+        packet.extend_from_slice(&self.tag.to_le_bytes());
+        packet.extend_from_slice(&plist_bytes);
+        packet
+    }
+
+    fn from_bytes(data: &[u8]) -> Result<Self, UsbmuxdError> {
+        if data.len() < HEADER_SIZE {
+            return Err(UsbmuxdError::BadPacket("incomplete header"));
+        }
+        let field = |i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+        let size = field(0) as usize;
+        if size < HEADER_SIZE {
+            return Err(UsbmuxdError::BadPacket("size smaller than the header"));
+        }
+        if data.len() < size {
+            return Err(UsbmuxdError::BadPacket("incomplete payload"));
+        }
+        Ok(Self {
+            plist: plist::from_bytes(&data[HEADER_SIZE..size])?,
+            version: field(4),
+            message: field(8),
+            tag: field(12),
+        })
+    }
+}
+
+/// Reads one whole packet off the stream
+async fn read_packet(stream: &mut UnixStream) -> Result<RawPacket, UsbmuxdError> {
+    let mut packet = vec![0u8; HEADER_SIZE];
+    stream.read_exact(&mut packet).await?;
+    let size = u32::from_le_bytes(packet[0..4].try_into().unwrap()) as usize;
+    if !(HEADER_SIZE..=MAX_PACKET_SIZE).contains(&size) {
+        return Err(UsbmuxdError::BadPacket("bad packet size"));
+    }
+    packet.resize(size, 0);
+    stream.read_exact(&mut packet[HEADER_SIZE..]).await?;
+    RawPacket::from_bytes(&packet)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionType {
+    Usb,
+    Network,
+    Other(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UsbmuxdDevice {
+    pub device_id: u64,
+    pub udid: String,
+    pub connection_type: ConnectionType,
+    pub network_address: Option<IpAddr>,
+    pub product_id: Option<u64>,
+}
+
+impl UsbmuxdDevice {
+    /// Parses an Attached message, or an entry in ListDevices
+    fn from_plist(message: &Dictionary) -> Result<Self, UsbmuxdError> {
+        let properties = message
+            .get("Properties")
+            .and_then(|p| p.as_dictionary())
+            .ok_or_else(|| UsbmuxdError::UnexpectedResponse("device has no Properties".into()))?;
+        let device_id = properties
+            .get("DeviceID")
+            .or_else(|| message.get("DeviceID"))
+            .and_then(|d| d.as_unsigned_integer())
+            .ok_or_else(|| UsbmuxdError::UnexpectedResponse("device has no DeviceID".into()))?;
+        let udid = properties
+            .get("SerialNumber")
+            .and_then(|s| s.as_string())
+            .ok_or_else(|| UsbmuxdError::UnexpectedResponse("device has no SerialNumber".into()))?
+            .to_string();
+        let connection_type = match properties.get("ConnectionType").and_then(|c| c.as_string()) {
+            Some("USB") => ConnectionType::Usb,
+            Some("Network") => ConnectionType::Network,
+            Some(other) => ConnectionType::Other(other.to_string()),
+            None => ConnectionType::Other(String::new()),
+        };
+        let network_address = match properties.get("NetworkAddress") {
+            Some(Value::Data(d)) => parse_sockaddr(d),
+            Some(Value::String(s)) => s.parse().ok(),
+            _ => None,
+        };
+        Ok(Self {
+            device_id,
+            udid,
+            connection_type,
+            network_address,
+            product_id: properties
+                .get("ProductID")
+                .and_then(|p| p.as_unsigned_integer()),
+        })
+    }
+}
+
+/// NetworkAddress is a raw sockaddr: length, family, then the address at the family's offset
+fn parse_sockaddr(data: &[u8]) -> Option<IpAddr> {
+    match data.get(1)? {
+        // AF_INET
+        0x02 => {
+            let addr: [u8; 4] = data.get(4..8)?.try_into().ok()?;
+            Some(Ipv4Addr::from(addr).into())
+        }
+        // AF_INET6 on Linux and on Apple
+        0x0A | 0x1E => {
+            let addr: [u8; 16] = data.get(8..24)?.try_into().ok()?;
+            Some(Ipv6Addr::from(addr).into())
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum UsbmuxdEvent {
+    Attached(UsbmuxdDevice),
+    Detached { device_id: u64 },
+    Paired { device_id: u64 },
+}
+
+pub struct UsbmuxdClient {
+    stream: UnixStream,
+    tag: u32,
+}
+
+impl UsbmuxdClient {
+    pub async fn connect(path: &str) -> Result<Self, UsbmuxdError> {
+        Ok(Self {
+            stream: UnixStream::connect(path).await?,
+            tag: 0,
+        })
+    }
+
+    fn request_plist(message_type: &str) -> Dictionary {
+        let mut request = Dictionary::new();
+        request.insert("MessageType".into(), message_type.into());
+        request.insert("ProgName".into(), PROG_NAME.into());
+        request.insert(
+            "ClientVersionString".into(),
+            format!("{PROG_NAME} {}", env!("CARGO_PKG_VERSION")).into(),
+        );
+        request.insert("kLibUSBMuxVersion".into(), 3.into());
+        request
+    }
+
+    async fn send(&mut self, request: Dictionary) -> Result<u32, UsbmuxdError> {
+        self.tag += 1;
+        let packet = RawPacket::new(request, self.tag);
+        self.stream.write_all(&packet.to_bytes()).await?;
+        Ok(self.tag)
+    }
+
+    /// Sends the request and reads its response
+    async fn request(&mut self, request: Dictionary) -> Result<Dictionary, UsbmuxdError> {
+        let tag = self.send(request).await?;
+        let response = read_packet(&mut self.stream).await?;
+        if response.tag != tag {
+            return Err(UsbmuxdError::BadPacket(
+                "response tag doesn't match the request",
+            ));
+        }
+        debug!("usbmuxd response: {:?}", response.plist);
+        Ok(response.plist)
+    }
+
+    pub async fn list_devices(&mut self) -> Result<Vec<UsbmuxdDevice>, UsbmuxdError> {
+        let response = self.request(Self::request_plist("ListDevices")).await?;
+        response
+            .get("DeviceList")
+            .and_then(|d| d.as_array())
+            .ok_or_else(|| UsbmuxdError::UnexpectedResponse("no DeviceList".into()))?
+            .iter()
+            .map(|d| {
+                d.as_dictionary()
+                    .ok_or_else(|| {
+                        UsbmuxdError::UnexpectedResponse("device isn't a dictionary".into())
+                    })
+                    .and_then(UsbmuxdDevice::from_plist)
+            })
+            .collect()
+    }
+
+    /// Subscribes to devices coming and going. The current devices are sent as Attached first.
+    pub async fn listen(mut self) -> Result<UsbmuxdListener, UsbmuxdError> {
+        let response = self.request(Self::request_plist("Listen")).await?;
+        match response.get("Number").and_then(|n| n.as_unsigned_integer()) {
+            Some(0) => Ok(UsbmuxdListener {
+                stream: self.stream,
+            }),
+            Some(n) => Err(UsbmuxdError::Refused(n)),
+            None => Err(UsbmuxdError::UnexpectedResponse(format!("{response:?}"))),
+        }
+    }
+
+    /// netmuxd extension, starts tracking a network device
+    pub async fn add_device(&mut self, ip: &str, udid: &str) -> Result<(), UsbmuxdError> {
+        let mut request = Self::request_plist("AddDevice");
+        request.insert("ConnectionType".into(), "Network".into());
+        request.insert(
+            "ServiceName".into(),
+            format!("_{SERVICE_NAME}._{SERVICE_PROTOCOL}.local").into(),
+        );
+        request.insert("IPAddress".into(), ip.into());
+        request.insert("DeviceID".into(), udid.into());
+
+        let response = self.request(request).await?;
+        match response.get("Result").and_then(|r| r.as_unsigned_integer()) {
+            Some(1) => Ok(()),
+            Some(n) => Err(UsbmuxdError::Refused(n)),
+            None => Err(UsbmuxdError::UnexpectedResponse(format!("{response:?}"))),
+        }
+    }
+
+    /// netmuxd extension, stops tracking a network device. netmuxd doesn't answer this.
+    pub async fn remove_device(&mut self, udid: &str) -> Result<(), UsbmuxdError> {
+        let mut request = Self::request_plist("RemoveDevice");
+        request.insert("DeviceID".into(), udid.into());
+        self.send(request).await?;
+        Ok(())
+    }
+}
+
+pub struct UsbmuxdListener {
+    stream: UnixStream,
+}
+
+impl UsbmuxdListener {
+    pub async fn next_event(&mut self) -> Result<UsbmuxdEvent, UsbmuxdError> {
+        loop {
+            let packet = read_packet(&mut self.stream).await?;
+            let device_id = || {
+                packet
+                    .plist
+                    .get("DeviceID")
+                    .and_then(|d| d.as_unsigned_integer())
+                    .ok_or_else(|| UsbmuxdError::UnexpectedResponse("event has no DeviceID".into()))
+            };
+            match packet.plist.get("MessageType").and_then(|m| m.as_string()) {
+                Some("Attached") => {
+                    return Ok(UsbmuxdEvent::Attached(UsbmuxdDevice::from_plist(
+                        &packet.plist,
+                    )?))
+                }
+                Some("Detached") => {
+                    return Ok(UsbmuxdEvent::Detached {
+                        device_id: device_id()?,
+                    })
+                }
+                Some("Paired") => {
+                    return Ok(UsbmuxdEvent::Paired {
+                        device_id: device_id()?,
+                    })
+                }
+                other => debug!("Ignoring usbmuxd event {other:?}"),
+            }
+        }
+    }
+}
+
+/// Asks netmuxd to start tracking a network device.
+/// Returns whether netmuxd accepted the device.
+pub async fn add_device(ip: &str, udid: &str) -> bool {
+    let res = async {
+        UsbmuxdClient::connect(NETMUXD_SOCKET)
+            .await?
+            .add_device(ip, udid)
+            .await
+    }
+    .await;
+    match res {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Failed to add {udid} to netmuxd, is it running? {e}");
+            false
+        }
+    }
+}
+
+/// Asks netmuxd to stop tracking a network device
+pub async fn remove_device(udid: &str) {
+    let res = async {
+        UsbmuxdClient::connect(NETMUXD_SOCKET)
+            .await?
+            .remove_device(udid)
+            .await
+    }
+    .await;
+    if let Err(e) = res {
+        error!("Failed to remove {udid} from netmuxd: {e}");
+    }
+}
+
+/// The devices netmuxd knows about, for diagnostics
+pub async fn list_devices() -> Result<Vec<UsbmuxdDevice>, UsbmuxdError> {
+    UsbmuxdClient::connect(NETMUXD_SOCKET)
+        .await?
+        .list_devices()
+        .await
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RecentEvent {
+    at: String,
+    #[serde(flatten)]
+    event: UsbmuxdEvent,
+}
+
+/// Follows netmuxd's device events for diagnostics, reconnecting if netmuxd goes away
+pub async fn watch() {
+    loop {
+        let res: Result<(), UsbmuxdError> = async {
+            let mut listener = UsbmuxdClient::connect(NETMUXD_SOCKET)
+                .await?
+                .listen()
+                .await?;
+            loop {
+                let event = listener.next_event().await?;
+                debug!("netmuxd event: {event:?}");
+                let mut events = RECENT_EVENTS.lock().unwrap();
+                if events.len() == RECENT_EVENTS_LEN {
+                    events.pop_front();
+                }
+                events.push_back(RecentEvent {
+                    at: crate::debug_server::timestamp(),
+                    event,
+                });
+            }
+        }
+        .await;
+        if let Err(e) = res {
+            debug!("Lost netmuxd's event stream: {e}");
+        }
+        tokio::time::sleep(WATCH_RETRY_DELAY).await;
+    }
+}
+
+/// The last RECENT_EVENTS_LEN device events, oldest first
+pub fn recent_events() -> Vec<RecentEvent> {
+    RECENT_EVENTS.lock().unwrap().iter().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use tokio::{net::UnixListener, task::JoinHandle};
+
+    use super::*;
+
+    fn socket_path() -> String {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        std::env::temp_dir()
+            .join(format!(
+                "jitstreamer-usbmuxd-{}-{}.sock",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn dict(entries: &[(&str, Value)]) -> Dictionary {
+        entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect()
+    }
+
+    fn device_plist(id: u64, udid: &str, connection: &str, address: Option<Vec<u8>>) -> Value {
+        let mut properties = dict(&[
+            ("DeviceID", id.into()),
+            ("SerialNumber", udid.into()),
+            ("ConnectionType", connection.into()),
+        ]);
+        if let Some(address) = address {
+            properties.insert("NetworkAddress".into(), Value::Data(address));
+        }
+        Value::Dictionary(dict(&[
+            ("MessageType", "Attached".into()),
+            ("DeviceID", id.into()),
+            ("Properties", Value::Dictionary(properties)),
+        ]))
+    }
+
+    /// Accepts one client and answers each request with the next reply, if there is one.
+    /// Then sends the events and waits for the client to hang up.
+    /// Resolves to the requests it got.
+    async fn fake_usbmuxd(
+        path: &str,
+        replies: Vec<Option<Dictionary>>,
+        events: Vec<Dictionary>,
+    ) -> JoinHandle<Vec<RawPacket>> {
+        let listener = UnixListener::bind(path).unwrap();
+        tokio::task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut requests = Vec::new();
+            for reply in replies {
+                let request = read_packet(&mut stream).await.unwrap();
+                if let Some(reply) = reply {
+                    let packet = RawPacket::new(reply, request.tag);
+                    stream.write_all(&packet.to_bytes()).await.unwrap();
+                }
+                requests.push(request);
+            }
+            for event in events {
+                stream
+                    .write_all(&RawPacket::new(event, 0).to_bytes())
+                    .await
+                    .unwrap();
+            }
+            stream.read_to_end(&mut Vec::new()).await.ok();
+            requests
+        })
+    }
+
+    fn message_type(packet: &RawPacket) -> &str {
+        packet
+            .plist
+            .get("MessageType")
+            .unwrap()
+            .as_string()
+            .unwrap()
+    }
+
+    #[test]
+    fn packet_round_trip() {
+        let packet = RawPacket::new(dict(&[("MessageType", "ListDevices".into())]), 7);
+        let bytes = packet.to_bytes();
+        assert_eq!(
+            u32::from_le_bytes(bytes[0..4].try_into().unwrap()) as usize,
+            bytes.len()
+        );
+        assert_eq!(RawPacket::from_bytes(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn rejects_bad_packets() {
+        let bytes = RawPacket::new(Dictionary::new(), 1).to_bytes();
+        assert!(matches!(
+            RawPacket::from_bytes(&bytes[..10]),
+            Err(UsbmuxdError::BadPacket(_))
+        ));
+        assert!(matches!(
+            RawPacket::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(UsbmuxdError::BadPacket(_))
+        ));
+        let mut small = bytes.clone();
+        small[0..4].copy_from_slice(&4u32.to_le_bytes());
+        assert!(matches!(
+            RawPacket::from_bytes(&small),
+            Err(UsbmuxdError::BadPacket(_))
+        ));
+    }
+
+    #[test]
+    fn parses_sockaddrs() {
+        let v4 = [0x10, 0x02, 0, 0, 10, 7, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(parse_sockaddr(&v4), Some("10.7.0.2".parse().unwrap()));
+
+        let mut v6 = vec![0x1C, 0x1E, 0, 0, 0, 0, 0, 0];
+        v6.extend_from_slice(&"fd00::2".parse::<Ipv6Addr>().unwrap().octets());
+        v6.extend_from_slice(&[0, 0, 0, 0]);
+        assert_eq!(parse_sockaddr(&v6), Some("fd00::2".parse().unwrap()));
+
+        assert_eq!(parse_sockaddr(&[0x10, 0x02, 0, 0]), None);
+        assert_eq!(parse_sockaddr(&[0x10, 0x99]), None);
+    }
+
+    #[tokio::test]
+    async fn lists_devices() {
+        let path = socket_path();
+        let mut v4 = vec![0x10, 0x02, 0, 0, 10, 7, 0, 2];
+        v4.resize(16, 0);
+        let reply = dict(&[(
+            "DeviceList",
+            Value::Array(vec![
+                device_plist(1, "usb-udid", "USB", None),
+                device_plist(2, "network-udid", "Network", Some(v4)),
+            ]),
+        )]);
+        let server = fake_usbmuxd(&path, vec![Some(reply)], Vec::new()).await;
+
+        let mut client = UsbmuxdClient::connect(&path).await.unwrap();
+        let devices = client.list_devices().await.unwrap();
+        drop(client);
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].udid, "usb-udid");
+        assert_eq!(devices[0].connection_type, ConnectionType::Usb);
+        assert_eq!(devices[0].network_address, None);
+        assert_eq!(devices[1].device_id, 2);
+        assert_eq!(devices[1].connection_type, ConnectionType::Network);
+        assert_eq!(
+            devices[1].network_address,
+            Some("10.7.0.2".parse().unwrap())
+        );
+
+        let requests = server.await.unwrap();
+        assert_eq!(message_type(&requests[0]), "ListDevices");
+        assert_eq!(requests[0].version, PLIST_VERSION);
+        assert_eq!(requests[0].message, PLIST_MESSAGE);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn listens_for_events() {
+        let path = socket_path();
+        let events = vec![
+            device_plist(3, "udid-3", "Network", None)
+                .into_dictionary()
+                .unwrap(),
+            dict(&[("MessageType", "Unknown".into())]),
+            dict(&[("MessageType", "Paired".into()), ("DeviceID", 3.into())]),
+            dict(&[("MessageType", "Detached".into()), ("DeviceID", 3.into())]),
+        ];
+        let ok = dict(&[("MessageType", "Result".into()), ("Number", 0.into())]);
+        let server = fake_usbmuxd(&path, vec![Some(ok)], events).await;
+
+        let client = UsbmuxdClient::connect(&path).await.unwrap();
+        let mut listener = client.listen().await.unwrap();
+        match listener.next_event().await.unwrap() {
+            UsbmuxdEvent::Attached(d) => assert_eq!(d.udid, "udid-3"),
+            e => panic!("expected Attached, got {e:?}"),
+        }
+        assert_eq!(
+            listener.next_event().await.unwrap(),
+            UsbmuxdEvent::Paired { device_id: 3 }
+        );
+        assert_eq!(
+            listener.next_event().await.unwrap(),
+            UsbmuxdEvent::Detached { device_id: 3 }
+        );
+        drop(listener);
+
+        let requests = server.await.unwrap();
+        assert_eq!(message_type(&requests[0]), "Listen");
+        std::fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn listen_refused() {
+        let path = socket_path();
+        let refused = dict(&[("MessageType", "Result".into()), ("Number", 3.into())]);
+        let server = fake_usbmuxd(&path, vec![Some(refused)], Vec::new()).await;
+
+        let client = UsbmuxdClient::connect(&path).await.unwrap();
+        assert!(matches!(
+            client.listen().await,
+            Err(UsbmuxdError::Refused(3))
+        ));
+        server.await.unwrap();
+        std::fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn adds_and_removes_devices() {
+        let path = socket_path();
+        let replies = vec![
+            Some(dict(&[("Result", 1.into())])),
+            Some(dict(&[("Result", 0.into())])),
+            None,
+        ];
+        let server = fake_usbmuxd(&path, replies, Vec::new()).await;
+
+        let mut client = UsbmuxdClient::connect(&path).await.unwrap();
+        client.add_device("10.7.0.2", "udid-1").await.unwrap();
+        assert!(matches!(
+            client.add_device("10.7.0.3", "udid-2").await,
+            Err(UsbmuxdError::Refused(0))
+        ));
+        client.remove_device("udid-1").await.unwrap();
+        drop(client);
+
+        let requests = server.await.unwrap();
+        assert_eq!(message_type(&requests[0]), "AddDevice");
+        assert_eq!(
+            requests[0].plist.get("IPAddress").unwrap().as_string(),
+            Some("10.7.0.2")
+        );
+        assert_eq!(
+            requests[0].plist.get("DeviceID").unwrap().as_string(),
+            Some("udid-1")
+        );
+        assert_eq!(
+            requests[0].plist.get("ServiceName").unwrap().as_string(),
+            Some("_apple-mobdev2._tcp.local")
+        );
+        assert_eq!(message_type(&requests[2]), "RemoveDevice");
+        assert_eq!(
+            requests[2].plist.get("DeviceID").unwrap().as_string(),
+            Some("udid-1")
+        );
+        // Each request gets its own tag so responses can be matched up
+        assert_eq!(
+            requests.iter().map(|r| r.tag).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        std::fs::remove_file(path).ok();
+    }
+}
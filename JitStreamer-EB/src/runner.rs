@@ -1,35 +1,307 @@
 // Jackson Coxson
-// Runs the Python shims until it's written in Rust
+// Launch workers that pull jobs off the launch queue
 
 use std::{
-    io::{BufRead, BufReader},
-    process::{Command, Stdio},
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
 };
 
-use log::{info, warn};
+use log::{error, info, warn};
+use serde::Serialize;
+use tokio::{
+    sync::{oneshot, watch, Mutex},
+    task::JoinHandle,
+};
+
+use crate::{
+    debug_server::{self, JobKind, JobStatus},
+    JitStreamerState,
+};
+
+/// How long a worker waits after crashing, doubled for each crash in a row
+const RESTART_DELAY_MIN: Duration = Duration::from_secs(1);
+const RESTART_DELAY_MAX: Duration = Duration::from_secs(60);
+
+/// Claimed jobs that can be cancelled. The worker replies whether it stopped before the app launched.
+pub type Cancellations = Arc<Mutex<HashMap<i64, oneshot::Sender<oneshot::Sender<bool>>>>>;
+
+/// What each worker is doing, by worker number
+pub type Workers = Arc<Mutex<BTreeMap<u32, WorkerInfo>>>;
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct WorkerInfo {
+    #[serde(flatten)]
+    pub state: WorkerState,
+    /// Panics and launcher crashes since the server started
+    pub crashes: u64,
+    /// Crashes since the last job that ran cleanly, sets the backoff
+    pub consecutive_crashes: u32,
+    pub last_crash: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum WorkerState {
+    #[default]
+    Idle,
+    Busy {
+        job_id: i64,
+        udid: String,
+        bundle_id: Option<String>,
+        task: String,
+        backend: &'static str,
+        since: String,
+    },
+    /// The worker crashed and is backing off
+    Failed {
+        error: String,
+        since: String,
+    },
+    Stopped,
+}
+
+/// The running workers. Dropping this leaves them running, call `shutdown` to stop them.
+pub struct WorkerPool {
+    shutdown: watch::Sender<bool>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// Stops taking new jobs and waits for the jobs in progress to finish
+    pub async fn shutdown(self) {
+        info!("Stopping launch workers");
+        self.shutdown.send(true).ok();
+        for handle in self.handles {
+            handle.await.ok();
+        }
+        info!("Launch workers stopped");
+    }
+}
+
+pub fn run(count: u32, state: JitStreamerState) -> WorkerPool {
+    let (shutdown, _) = watch::channel(false);
+
+    let mut stopping = shutdown.subscribe();
+    let mut handles = vec![tokio::task::spawn(async move {
+        loop {
+            debug_server::requeue_expired();
+            debug_server::prune();
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(10)) => {}
+                _ = stopping.wait_for(|s| *s) => return,
+            }
+        }
+    })];
+
+    info!("Starting {count} launch workers");
+    for worker in 0..count {
+        handles.push(tokio::task::spawn(supervise(
+            worker,
+            state.clone(),
+            shutdown.subscribe(),
+        )));
+    }
+
+    WorkerPool { shutdown, handles }
+}
+
+/// Keeps a worker running, restarting it if it panics
+async fn supervise(worker: u32, state: JitStreamerState, mut stopping: watch::Receiver<bool>) {
+    loop {
+        let res = tokio::task::spawn(work(worker, state.clone(), stopping.clone())).await;
+        let error = match res {
+            Ok(()) => {
+                set_state(&state, worker, WorkerState::Stopped).await;
+                return;
+            }
+            Err(e) => e.to_string(),
+        };
 
-pub fn run(path: &str, count: u32) {
-    info!("Running {}...", path);
-    for _ in 0..count {
-        let path = path.to_string();
-        std::thread::spawn(move || {
+        // Its job's lease will run out and the job will be requeued
+        error!("Worker {worker} panicked: {error}");
+        let delay = crashed(&state, worker, error).await;
+        if !back_off(&mut stopping, delay).await {
+            set_state(&state, worker, WorkerState::Stopped).await;
+            return;
+        }
+        warn!("Restarting worker {worker}");
+    }
+}
+
+async fn work(worker: u32, state: JitStreamerState, mut stopping: watch::Receiver<bool>) {
+    loop {
+        set_state(&state, worker, WorkerState::Idle).await;
+        let job = tokio::select! {
+            job = debug_server::next_job() => job,
+            _ = stopping.wait_for(|s| *s) => return,
+        };
+        let backend = state.backends.pick(&job);
+        info!(
+            "Worker {worker} claimed job to {} for UDID: {}, Ordinal: {}, attempt {}, backend {}",
+            job.kind,
+            job.udid,
+            job.ordinal,
+            job.attempt,
+            backend.name()
+        );
+        set_state(
+            &state,
+            worker,
+            WorkerState::Busy {
+                job_id: job.ordinal,
+                udid: job.udid.clone(),
+                bundle_id: match &job.kind {
+                    JobKind::Launch { bundle_id, .. } => Some(bundle_id.clone()),
+                    JobKind::Attach(_) => None,
+                },
+                task: job.kind.to_string(),
+                backend: backend.name(),
+                since: debug_server::timestamp(),
+            },
+        )
+        .await;
+
+        let (cancel_sender, mut cancel) = oneshot::channel();
+        state
+            .cancellations
+            .lock()
+            .await
+            .insert(job.ordinal, cancel_sender);
+
+        // Keep renewing the claim while working, if this worker dies the job gets requeued.
+        // Stops with Err(Some) when the job is cancelled, Err(None) when the claim is lost.
+        let res = {
+            let work = backend.run(&state, worker, &job);
+            tokio::pin!(work);
             loop {
-                // Run the Python shim
-                let mut child = Command::new("python3")
-                    .args(["-u", &path])
-                    .stdout(Stdio::piped())
-                    .spawn()
-                    .unwrap();
-                let stdout = child.stdout.take().unwrap();
-
-                // Stream output.
-                let lines = BufReader::new(stdout).lines();
-                for line in lines {
-                    println!("{}", line.unwrap());
+                tokio::select! {
+                    res = &mut work => break Ok(res),
+                    Ok(reply) = &mut cancel => break Err(Some(reply)),
+                    _ = tokio::time::sleep(debug_server::LEASE_DURATION / 3) => {
+                        if !debug_server::renew_lease(job.ordinal, job.attempt) {
+                            break Err(None);
+                        }
+                    }
                 }
+            }
+        };
+        state.cancellations.lock().await.remove(&job.ordinal);
+        if let Ok(r) = &res {
+            state.backends.record(backend.name(), r.is_ok());
+        }
 
-                warn!("Python shim stopped!");
+        let crash = match res {
+            Err(None) => {
+                // Requeued or finished without us, whoever has it now owns the outcome
+                warn!(
+                    "Worker {worker} lost its claim on job to {}, dropping it",
+                    job.kind
+                );
+                None
+            }
+            Err(Some(reply)) => {
+                // The launch was dropped wherever it was, see how far it got
+                let before_launch = matches!(
+                    debug_server::get_job(job.ordinal),
+                    Some(j) if j.status == JobStatus::Claimed
+                );
+                debug_server::cancel_claimed(job.ordinal, job.attempt);
+                info!(
+                    "Worker {worker} cancelled job to {}, before launch: {before_launch}",
+                    job.kind
+                );
+                reply.send(before_launch).ok();
+                None
+            }
+            Ok(Ok(pid)) => {
+                info!("Worker {worker} enabled JIT for PID {pid} ({})", job.kind);
+                let status = match &job.kind {
+                    JobKind::Launch { options, .. } if options.persistent => JobStatus::Attached,
+                    _ => JobStatus::Detached,
+                };
+                debug_server::complete(job.ordinal, job.attempt, status, pid);
+                None
+            }
+            Ok(Err(e)) => {
+                error!("Worker {worker} failed to {}: {e}", job.kind);
+                debug_server::fail(job.ordinal, job.attempt, e.to_string(), e.code());
+                e.is_crash().then(|| e.to_string())
+            }
+        };
+        info!(
+            "Worker {worker} finished processing ordinal {}",
+            job.ordinal
+        );
+
+        match crash {
+            Some(error) => {
+                let delay = crashed(&state, worker, error).await;
+                warn!("Worker {worker} crashed, waiting {delay:?} before the next job");
+                if !back_off(&mut stopping, delay).await {
+                    return;
+                }
             }
-        });
+            None => {
+                state
+                    .workers
+                    .lock()
+                    .await
+                    .entry(worker)
+                    .or_default()
+                    .consecutive_crashes = 0;
+            }
+        }
+    }
+}
+
+async fn set_state(state: &JitStreamerState, worker: u32, worker_state: WorkerState) {
+    state.workers.lock().await.entry(worker).or_default().state = worker_state;
+}
+
+/// Counts the crash and marks the worker failed, returning how long it should back off
+async fn crashed(state: &JitStreamerState, worker: u32, error: String) -> Duration {
+    let mut workers = state.workers.lock().await;
+    let info = workers.entry(worker).or_default();
+    info.crashes += 1;
+    info.consecutive_crashes += 1;
+    info.last_crash = Some(debug_server::timestamp());
+    info.state = WorkerState::Failed {
+        error,
+        since: debug_server::timestamp(),
+    };
+    RESTART_DELAY_MIN
+        .saturating_mul(2u32.saturating_pow(info.consecutive_crashes - 1))
+        .min(RESTART_DELAY_MAX)
+}
+
+/// Sleeps for the backoff, returning false if the server is shutting down
+async fn back_off(stopping: &mut watch::Receiver<bool>, delay: Duration) -> bool {
+    tokio::select! {
+        _ = tokio::time::sleep(delay) => true,
+        _ = stopping.wait_for(|s| *s) => false,
+    }
+}
+
+/// Cancels a job, stopping its worker if it's been claimed.
+/// Returns whether the cancellation happened before the app launched, or None if
+/// the job had already finished.
+pub async fn cancel(cancellations: &Cancellations, ordinal: i64) -> Option<bool> {
+    // A worker may be between claiming the job and registering it
+    for _ in 0..10 {
+        if debug_server::cancel_queued(ordinal) {
+            return Some(true);
+        }
+        if let Some(sender) = cancellations.lock().await.remove(&ordinal) {
+            let (reply, receiver) = oneshot::channel();
+            sender.send(reply).ok()?;
+            return receiver.await.ok();
+        }
+        match debug_server::get_job(ordinal) {
+            Some(job) if job.status == JobStatus::Claimed => {}
+            _ => return None,
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
     }
+    None
 }
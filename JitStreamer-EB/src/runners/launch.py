@@ -1,10 +1,9 @@
 # Jackson Coxson + ny
 
 import asyncio
-import aiosqlite
+import json
 import socket
-
-import netmuxd
+import sys
 
 from pymobiledevice3.services.dvt.instruments.process_control import ProcessControl
 from pymobiledevice3.services.dvt.dvt_secure_socket_proxy import (
@@ -13,17 +12,12 @@ from pymobiledevice3.services.dvt.dvt_secure_socket_proxy import (
 from pymobiledevice3.tunneld.api import async_get_tunneld_device_by_udid
 
 
-async def launch_app(udid, ip, bundle_id):
+async def launch_app(udid, ip, bundle_id, options):
     """
-    Launches the app and enables JIT. Returns a success message or raises an error.
+    Launches the app and enables JIT. Returns the app's PID or raises an error.
+    The server has netmuxd tracking the device while this runs.
     """
 
-    if not await netmuxd.add_device(ip, udid):
-        raise RuntimeError(f"Failed to add device {udid} to netmuxd")
-
-    # Sleep for a short period to allow the device to be added
-    await asyncio.sleep(2)
-
     device = None
     for _ in range(15):
         try:
@@ -45,10 +39,10 @@ async def launch_app(udid, ip, bundle_id):
             process_control = ProcessControl(dvt)
             app = process_control.launch(
                 bundle_id=bundle_id,
-                arguments={},
-                kill_existing=False,
+                arguments=options.get("arguments", []),
+                kill_existing=options.get("kill_existing", False),
                 start_suspended=True,
-                environment={},
+                environment=options.get("environment", {}),
             )
 
             try:
@@ -75,94 +69,35 @@ async def launch_app(udid, ip, bundle_id):
                 if out.startswith("$T11thread") or "+" in out:
                     s.sendall(b"$D#44")
                     new = s.recv(16)
-                    if any(x in new for x in (b"$T11thread", b"$OK#00", b"+")):
-                        return
-                    else:
+                    if not any(x in new for x in (b"$T11thread", b"$OK#00", b"+")):
                         print(f"[WARN] Failed to detach process {app}")
+                    return app
                 else:
                     raise Exception(f"Failed to attach process {app}")
     except Exception as e:
         raise RuntimeError(f"Error launching app {bundle_id} on {udid}: {str(e)}")
 
 
-async def process_launch_queue():
+async def main(udid, ip, bundle_id, options):
     """
-    Reads from the SQLite database and processes pending app launches.
+    Runs a single launch for the server's Python backend.
+    Prints the PID on success, or the error and exits non-zero.
     """
-    db_path = "jitstreamer.db"
-
-    async with aiosqlite.connect(db_path) as db:
-        while True:
-            await db.execute("BEGIN IMMEDIATE")
-            # Begin a transaction to claim a pending job
-            async with db.execute(
-                """
-                SELECT udid, ip, bundle_id, ordinal
-                FROM launch_queue
-                WHERE status = 0
-                ORDER BY ordinal ASC
-                LIMIT 1
-                """
-            ) as cursor:
-                row = await cursor.fetchone()
-
-            if not row:
-                await db.commit()
-                await asyncio.sleep(1)
-                continue
-
-            udid, ip, bundle_id, ordinal = row
-
-            # Lock the job by setting the status to 1 (in progress)
-            await db.execute(
-                "UPDATE launch_queue SET status = 1 WHERE ordinal = ?",
-                (ordinal,),
-            )
-            await db.commit()
-
-            print(
-                f"[INFO] Claimed launch job for UDID: {udid}, Bundle ID: {bundle_id}, Ordinal: {ordinal}"
-            )
-
-            try:
-                # Process the launch
-                result = await asyncio.wait_for(
-                    launch_app(udid, ip, bundle_id), timeout=60
-                )
-                print(f"[INFO] {result}")
-
-                # Delete the device from the queue
-                await db.execute(
-                    "DELETE FROM launch_queue WHERE ordinal = ?",
-                    (ordinal,),
-                )
-            except asyncio.TimeoutError:
-                print("[ERROR] Launch timed out")
-
-                # Update the database with the error
-                await db.execute(
-                    "UPDATE launch_queue SET status = 2, error = ? WHERE ordinal = ?",
-                    ("Timeout", ordinal),
-                )
-            except Exception as e:
-                print(f"[ERROR] {e}")
-
-                # Update the database with the error
-                await db.execute(
-                    "UPDATE launch_queue SET status = 2, error = ? WHERE ordinal = ?",
-                    (str(e), ordinal),
-                )
-
-            await db.commit()
-            print(f"[INFO] Finished processing ordinal {ordinal}")
-
-            # Remove the UDID from netmuxd
-            # Connect to the unix socket and send the UDID to remove
-            await netmuxd.remove_device(udid)
+    try:
+        pid = await asyncio.wait_for(
+            launch_app(udid, ip, bundle_id, options), timeout=60
+        )
+        print(f"[PID] {pid}")
+    except asyncio.TimeoutError:
+        print("[ERROR] Launch timed out")
+        sys.exit(1)
+    except Exception as e:
+        print(f"[ERROR] {e}")
+        sys.exit(1)
 
 
 if __name__ == "__main__":
-    try:
-        asyncio.run(process_launch_queue())
-    except KeyboardInterrupt:
-        print("Shutting down gracefully...")
+    if len(sys.argv) != 5:
+        print("[ERROR] Usage: launch.py <udid> <ip> <bundle_id> <options json>")
+        sys.exit(2)
+    asyncio.run(main(sys.argv[1], sys.argv[2], sys.argv[3], json.loads(sys.argv[4])))
@@ -0,0 +1,320 @@
+// Jackson Coxson
+// Debug sessions that stay attached to the app and service its breakpoints.
+// Some JIT schemes have the app trap into the debugger to ask for executable memory,
+// so detaching right after attach isn't enough for them.
+
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+use axum::{extract::State, Json};
+use axum_client_ip::SecureClientIp;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::TcpStream,
+    sync::{watch, Mutex},
+};
+
+use crate::{
+    common,
+    debugserver::DebugserverClient,
+    gdb::StopReply,
+    heartbeat::{self, NewHeartbeatSender},
+    launch::{self, LaunchError},
+    JitStreamerState,
+};
+
+pub type Sessions = Arc<Mutex<HashMap<String, watch::Receiver<SessionState>>>>;
+
+/// Whether the device has a session that hasn't ended. Ended ones are kept for /session.
+pub async fn is_running(sessions: &Sessions, udid: &str) -> bool {
+    sessions
+        .lock()
+        .await
+        .get(udid)
+        .is_some_and(|s| s.borrow().running)
+}
+
+const SIGTRAP: u8 = 5;
+// arm64 register numbers as debugserver numbers them
+const REG_X0: u32 = 0;
+const REG_X1: u32 = 1;
+const REG_PC: u32 = 32;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionState {
+    pub bundle_id: String,
+    pub pid: u64,
+    pub running: bool,
+    pub stops_handled: u64,
+    pub last_stop: Option<String>,
+    pub ended_reason: Option<String>,
+}
+
+/// What to do when the app stops
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopAction {
+    /// Resume without delivering the signal
+    Continue,
+    /// Step over the trapping instruction and resume
+    Skip,
+    /// Allocate an RX region of x1 bytes, return its address in x0, step over the trap
+    PrepareRegion,
+    /// Detach and end the session
+    Detach,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StopRule {
+    #[serde(default = "default_signal")]
+    pub signal: u8,
+    /// Only match `brk #imm` traps with this immediate
+    pub brk: Option<u16>,
+    pub action: StopAction,
+}
+
+fn default_signal() -> u8 {
+    SIGTRAP
+}
+
+impl StopRule {
+    fn matches(&self, signal: u8, brk: Option<u16>) -> bool {
+        self.signal == signal && (self.brk.is_none() || self.brk == brk)
+    }
+}
+
+/// Reads the rules for an app from the file in SESSION_RULES, keyed by bundle ID.
+/// Read on every session start so rules can be changed without a restart.
+async fn load_rules(bundle_id: &str) -> Vec<StopRule> {
+    let path = std::env::var("SESSION_RULES").unwrap_or("session_rules.json".to_string());
+    let rules = match tokio::fs::read(&path).await {
+        Ok(r) => r,
+        Err(e) => {
+            debug!("No session rules at {path}: {e:?}");
+            return Vec::new();
+        }
+    };
+    match serde_json::from_slice::<HashMap<String, Vec<StopRule>>>(&rules) {
+        Ok(mut r) => r.remove(bundle_id).unwrap_or_default(),
+        Err(e) => {
+            warn!("Failed to parse session rules at {path}: {e:?}");
+            Vec::new()
+        }
+    }
+}
+
+/// Returns the immediate of a `brk #imm` instruction
+fn brk_immediate(instruction: &[u8]) -> Option<u16> {
+    let instruction = u32::from_le_bytes(instruction.try_into().ok()?);
+    if instruction & 0xffe0001f == 0xd4200000 {
+        Some(((instruction >> 5) & 0xffff) as u16)
+    } else {
+        None
+    }
+}
+
+/// Takes over an attached debugserver connection and keeps servicing the app until it exits.
+/// The device stays reachable and heartbeated for the whole session.
+pub fn start(
+    sessions: Sessions,
+    hb: NewHeartbeatSender,
+    udid: String,
+    ip: IpAddr,
+    bundle_id: String,
+    pid: u64,
+    debugserver: DebugserverClient<TcpStream>,
+) {
+    tokio::task::spawn(async move {
+        let (sender, receiver) = watch::channel(SessionState {
+            bundle_id: bundle_id.clone(),
+            pid,
+            running: true,
+            stops_handled: 0,
+            last_stop: None,
+            ended_reason: None,
+        });
+        sessions.lock().await.insert(udid.clone(), receiver);
+
+        match common::get_pairing_file(&udid).await {
+            Ok(pairing_file) => {
+                match heartbeat::heartbeat_thread(udid.clone(), ip, &pairing_file).await {
+                    Ok(s) => {
+                        hb.send(heartbeat::SendRequest::Store((
+                            udid.clone(),
+                            heartbeat::Owner::Session(pid),
+                            s,
+                        )))
+                        .await
+                        .ok();
+                    }
+                    Err(e) => warn!("Failed to heartbeat {udid} for the session: {e:?}"),
+                }
+            }
+            Err(e) => warn!("Failed to get pairing file for {udid}: {e:?}"),
+        }
+
+        let rules = load_rules(&bundle_id).await;
+        info!(
+            "Starting debug session for {bundle_id} ({pid}) on {udid} with {} rules",
+            rules.len()
+        );
+
+        let reason = match run(debugserver, &rules, &sender).await {
+            Ok(reason) => reason,
+            Err(e) => {
+                warn!("Debug session for {bundle_id} on {udid} failed: {e}");
+                e.to_string()
+            }
+        };
+        info!("Debug session for {bundle_id} on {udid} ended: {reason}");
+        sender.send_modify(|s| {
+            s.running = false;
+            s.ended_reason = Some(reason);
+        });
+
+        hb.send(heartbeat::SendRequest::Kill((
+            udid.clone(),
+            heartbeat::Owner::Session(pid),
+        )))
+        .await
+        .ok();
+        launch::release_device(&udid).await;
+    });
+}
+
+/// Services stops until the app exits, returning why the session ended
+async fn run(
+    mut debugserver: DebugserverClient<TcpStream>,
+    rules: &[StopRule],
+    state: &watch::Sender<SessionState>,
+) -> Result<String, LaunchError> {
+    let mut deliver = None;
+    loop {
+        let (signal, thread) = match debugserver.resume(deliver.take()).await? {
+            StopReply::Exited(code) => return Ok(format!("app exited with status {code}")),
+            StopReply::Terminated(signal) => {
+                return Ok(format!("app terminated by signal {signal}"))
+            }
+            StopReply::Signal {
+                signal,
+                thread: Some(thread),
+                ..
+            } => (signal, thread),
+            r => return Err(LaunchError::Debugserver(format!("{r:?}"))),
+        };
+
+        let pc = debugserver.read_register(thread, REG_PC).await?;
+        let brk = brk_immediate(&debugserver.read_memory(pc, 4).await?);
+        debug!("Stopped with signal {signal} at {pc:x}, brk {brk:?}");
+
+        match rules
+            .iter()
+            .find(|r| r.matches(signal, brk))
+            .map(|r| r.action)
+        {
+            Some(StopAction::Continue) => {}
+            Some(StopAction::Skip) => {
+                debugserver.write_register(thread, REG_PC, pc + 4).await?;
+            }
+            Some(StopAction::PrepareRegion) => {
+                let size = debugserver.read_register(thread, REG_X1).await?;
+                let address = debugserver.allocate(size, "rx").await?;
+                debug!("Prepared {size:x} bytes at {address:x}");
+                debugserver.write_register(thread, REG_X0, address).await?;
+                debugserver.write_register(thread, REG_PC, pc + 4).await?;
+            }
+            Some(StopAction::Detach) => {
+                debugserver.detach().await?;
+                return Ok("detached by rule".to_string());
+            }
+            // Not ours, let the app handle it
+            None => deliver = Some(signal),
+        }
+
+        state.send_modify(|s| {
+            s.stops_handled += 1;
+            s.last_stop = Some(match brk {
+                Some(imm) => format!("brk #{imm:#x} at {pc:#x}"),
+                None => format!("signal {signal} at {pc:#x}"),
+            });
+        });
+    }
+}
+
+#[derive(Serialize)]
+pub struct SessionReturn {
+    ok: bool,
+    session: Option<SessionState>,
+    error: Option<String>,
+}
+
+/// Returns the state of the device's debug session, if it has one
+pub async fn status(
+    ip: SecureClientIp,
+    State(state): State<JitStreamerState>,
+) -> Json<SessionReturn> {
+    let udid = match common::get_udid_from_ip(ip.0.to_string()).await {
+        Ok(u) => u,
+        Err(e) => {
+            return Json(SessionReturn {
+                ok: false,
+                session: None,
+                error: Some(e),
+            })
+        }
+    };
+
+    let session = state
+        .sessions
+        .lock()
+        .await
+        .get(&udid)
+        .map(|s| s.borrow().clone());
+    Json(SessionReturn {
+        ok: true,
+        session,
+        error: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_brk_immediates() {
+        // brk #0x69
+        assert_eq!(brk_immediate(&0xd4200d20u32.to_le_bytes()), Some(0x69));
+        // brk #0xf000, what __builtin_trap emits
+        assert_eq!(brk_immediate(&0xd43e0000u32.to_le_bytes()), Some(0xf000));
+        // nop
+        assert_eq!(brk_immediate(&0xd503201fu32.to_le_bytes()), None);
+        // hlt #0
+        assert_eq!(brk_immediate(&0xd4400000u32.to_le_bytes()), None);
+        assert_eq!(brk_immediate(&[0x20, 0x0d, 0x20]), None);
+    }
+
+    #[test]
+    fn matches_stop_rules() {
+        let any_trap = StopRule {
+            signal: SIGTRAP,
+            brk: None,
+            action: StopAction::Skip,
+        };
+        assert!(any_trap.matches(SIGTRAP, None));
+        assert!(any_trap.matches(SIGTRAP, Some(0x69)));
+        assert!(!any_trap.matches(11, None));
+
+        let brk = StopRule {
+            brk: Some(0x69),
+            ..any_trap
+        };
+        assert!(brk.matches(SIGTRAP, Some(0x69)));
+        assert!(!brk.matches(SIGTRAP, Some(0x70)));
+        assert!(!brk.matches(SIGTRAP, None));
+
+        let rule = serde_json::from_str::<StopRule>(r#"{"brk": 105, "action": "prepare_region"}"#)
+            .unwrap();
+        assert!(rule.matches(SIGTRAP, Some(105)));
+    }
+}
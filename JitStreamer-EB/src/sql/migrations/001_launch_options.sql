@@ -0,0 +1,3 @@
+alter table launch_queue add column arguments text; -- JSON array
+alter table launch_queue add column environment text; -- JSON object
+alter table launch_queue add column kill_existing int not null default 0;
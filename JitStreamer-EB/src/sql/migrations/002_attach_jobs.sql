@@ -0,0 +1,2 @@
+alter table launch_queue add column kind int not null default 0; -- 0: launch, 1: attach
+alter table launch_queue add column pid int; -- target of an attach job
@@ -0,0 +1 @@
+alter table launch_queue add column persistent int not null default 0;
@@ -0,0 +1,7 @@
+alter table launch_queue add column launched_pid int;
+alter table launch_queue add column error_code varchar(64);
+alter table launch_queue add column created_at datetime;
+alter table launch_queue add column claimed_at datetime;
+alter table launch_queue add column updated_at datetime;
+alter table launch_queue add column finished_at datetime;
+alter table launch_queue add column acknowledged int not null default 0; -- failure was reported through /status
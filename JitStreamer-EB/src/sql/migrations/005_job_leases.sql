@@ -0,0 +1,2 @@
+alter table launch_queue add column lease_expires datetime; -- the claiming worker must renew the claim before this
+alter table launch_queue add column attempts int not null default 0;
@@ -0,0 +1 @@
+alter table launch_queue add column forwarded int not null default 0; -- sent by another server's remote backend
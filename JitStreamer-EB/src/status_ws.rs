@@ -0,0 +1,130 @@
+// Jackson Coxson
+// Pushes launch status to the client as the queue changes, instead of making it poll /status
+
+use axum::extract::{
+    ws::{Message, WebSocket},
+    Path, WebSocketUpgrade,
+};
+use axum_client_ip::SecureClientIp;
+use log::debug;
+use tokio::sync::watch;
+
+use crate::{common, debug_server, JobStatusReturn, StatusReturn};
+
+/// Streams the same messages as /status for the device until it's done
+pub async fn handler(ws: WebSocketUpgrade, ip: SecureClientIp) -> axum::response::Response {
+    let ip = ip.0.to_string();
+    ws.on_upgrade(|s| async move { handle_socket(s, ip).await })
+}
+
+async fn handle_socket(mut socket: WebSocket, ip: String) {
+    let udid = match common::get_udid_from_ip(ip).await {
+        Ok(u) => u,
+        Err(e) => {
+            let msg = StatusReturn {
+                ok: false,
+                done: true,
+                error: Some(e),
+                position: 0,
+                in_progress: false,
+            };
+            socket
+                .send(Message::text(serde_json::to_string(&msg).unwrap()))
+                .await
+                .ok();
+            return;
+        }
+    };
+
+    let mut changes = debug_server::subscribe();
+    let mut last_sent = String::new();
+    loop {
+        changes.borrow_and_update();
+        let status = StatusReturn::from(debug_server::get_queue_info(&udid));
+        let msg = serde_json::to_string(&status).unwrap();
+        if msg != last_sent {
+            if socket.send(Message::text(msg.clone())).await.is_err() {
+                debug!("Failed to send status to websocket");
+                return;
+            }
+            last_sent = msg;
+        }
+        if status.done || !wait_for_change(&mut socket, &mut changes).await {
+            return;
+        }
+    }
+}
+
+/// Streams the same messages as /status/{job_id} until the job finishes
+pub async fn job_handler(
+    ws: WebSocketUpgrade,
+    ip: SecureClientIp,
+    Path(job_id): Path<i64>,
+) -> axum::response::Response {
+    let ip = ip.0.to_string();
+    ws.on_upgrade(move |s| async move { handle_job_socket(s, ip, job_id).await })
+}
+
+async fn handle_job_socket(mut socket: WebSocket, ip: String, job_id: i64) {
+    let udid = match common::get_udid_from_ip(ip).await {
+        Ok(u) => u,
+        Err(e) => {
+            send_job_error(&mut socket, e).await;
+            return;
+        }
+    };
+
+    let mut job = match debug_server::watch_job(job_id) {
+        Some(j) if j.borrow().udid == udid => j,
+        _ => {
+            send_job_error(&mut socket, format!("No job {job_id}")).await;
+            return;
+        }
+    };
+
+    loop {
+        let info = job.borrow_and_update().clone();
+        let finished = info.finished_at.is_some();
+        let msg = JobStatusReturn {
+            ok: true,
+            job: Some(info),
+            error: None,
+        };
+        if socket
+            .send(Message::text(serde_json::to_string(&msg).unwrap()))
+            .await
+            .is_err()
+        {
+            debug!("Failed to send job status to websocket");
+            return;
+        }
+        if finished || !wait_for_change(&mut socket, &mut job).await {
+            return;
+        }
+    }
+}
+
+async fn send_job_error(socket: &mut WebSocket, error: String) {
+    let msg = JobStatusReturn {
+        ok: false,
+        job: None,
+        error: Some(error),
+    };
+    socket
+        .send(Message::text(serde_json::to_string(&msg).unwrap()))
+        .await
+        .ok();
+}
+
+/// Waits for a change, returning false if the client went away
+async fn wait_for_change<T>(socket: &mut WebSocket, changes: &mut watch::Receiver<T>) -> bool {
+    loop {
+        tokio::select! {
+            r = changes.changed() => return r.is_ok(),
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return false,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
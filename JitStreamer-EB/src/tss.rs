@@ -0,0 +1,160 @@
+// Jackson Coxson
+// Personalization tickets from Apple's TSS, kept per device so a remount after a reboot
+// doesn't have to ask Apple again. Tickets are keyed by ECID and the build manifest they
+// were signed from, and dropped when the device turns one down.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info};
+use serde::Serialize;
+use sha2::Digest;
+
+/// How long a ticket is used before asking TSS again
+const MAX_AGE: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// ECID and SHA-256 of the build manifest
+type TicketKey = (u64, String);
+
+struct Ticket {
+    manifest: Vec<u8>,
+    fetched: Instant,
+}
+
+impl Ticket {
+    /// An IM4M is a DER sequence tagged IM4M, anything else didn't come from TSS
+    fn is_valid(&self) -> bool {
+        self.fetched.elapsed() < MAX_AGE
+            && self.manifest.first() == Some(&0x30)
+            && self.manifest.windows(4).any(|w| w == b"IM4M")
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TicketStats {
+    hits: u64,
+    misses: u64,
+    /// Cached tickets the device turned down
+    rejected: u64,
+    cached: usize,
+}
+
+#[derive(Default)]
+pub struct TicketCache {
+    tickets: Mutex<HashMap<TicketKey, Ticket>>,
+    stats: Mutex<TicketStats>,
+}
+
+impl TicketCache {
+    /// The cached ticket for the device and build manifest, if it's still usable
+    pub fn get(&self, ecid: u64, build_manifest: &[u8]) -> Option<Vec<u8>> {
+        let key = key(ecid, build_manifest);
+        let mut tickets = self.tickets.lock().unwrap();
+        let ticket = match tickets.get(&key) {
+            Some(t) if t.is_valid() => Some(t.manifest.clone()),
+            Some(_) => {
+                debug!("Dropping the stale ticket for {ecid:#x}");
+                tickets.remove(&key);
+                None
+            }
+            None => None,
+        };
+        let mut stats = self.stats.lock().unwrap();
+        if ticket.is_some() {
+            info!("TSS ticket cache hit for {ecid:#x}");
+            stats.hits += 1;
+        } else {
+            info!("TSS ticket cache miss for {ecid:#x}");
+            stats.misses += 1;
+        }
+        ticket
+    }
+
+    pub fn insert(&self, ecid: u64, build_manifest: &[u8], manifest: Vec<u8>) {
+        self.tickets.lock().unwrap().insert(
+            key(ecid, build_manifest),
+            Ticket {
+                manifest,
+                fetched: Instant::now(),
+            },
+        );
+    }
+
+    /// Forgets a ticket the device wouldn't take
+    pub fn reject(&self, ecid: u64, build_manifest: &[u8]) {
+        info!("Device {ecid:#x} rejected its cached TSS ticket");
+        self.tickets
+            .lock()
+            .unwrap()
+            .remove(&key(ecid, build_manifest));
+        self.stats.lock().unwrap().rejected += 1;
+    }
+
+    pub fn stats(&self) -> TicketStats {
+        TicketStats {
+            cached: self.tickets.lock().unwrap().len(),
+            ..*self.stats.lock().unwrap()
+        }
+    }
+}
+
+fn key(ecid: u64, build_manifest: &[u8]) -> TicketKey {
+    (ecid, format!("{:x}", sha2::Sha256::digest(build_manifest)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICKET: &[u8] = b"\x30\x82\x10\x00\x16\x04IM4M";
+
+    #[test]
+    fn caches_tickets() {
+        let cache = TicketCache::default();
+        assert_eq!(cache.get(1, b"manifest"), None);
+
+        cache.insert(1, b"manifest", TICKET.to_vec());
+        assert_eq!(cache.get(1, b"manifest").as_deref(), Some(TICKET));
+        // Another device, or another image for the same device
+        assert_eq!(cache.get(2, b"manifest"), None);
+        assert_eq!(cache.get(1, b"other manifest"), None);
+
+        cache.reject(1, b"manifest");
+        assert_eq!(cache.get(1, b"manifest"), None);
+
+        // Not an IM4M
+        cache.insert(1, b"manifest", b"<plist/>".to_vec());
+        assert_eq!(cache.get(1, b"manifest"), None);
+
+        assert_eq!(
+            cache.stats(),
+            TicketStats {
+                hits: 1,
+                misses: 5,
+                rejected: 1,
+                cached: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn expires_tickets() {
+        // Instants can't go back further than boot
+        let Some(fetched) = Instant::now().checked_sub(MAX_AGE) else {
+            return;
+        };
+        let ticket = Ticket {
+            manifest: TICKET.to_vec(),
+            fetched,
+        };
+        assert!(!ticket.is_valid());
+        assert!(Ticket {
+            fetched: Instant::now(),
+            ..ticket
+        }
+        .is_valid());
+    }
+}
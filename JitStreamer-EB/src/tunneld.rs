@@ -0,0 +1,337 @@
+// Jackson Coxson
+// Client for tunneld's HTTP API, which lists the tunnels to each device's RSD port.
+// Works with pymobiledevice3's tunneld and tunneld-rs.
+
+use std::{collections::HashMap, net::IpAddr, time::Duration};
+
+use axum::{extract::State, Json};
+use axum_client_ip::SecureClientIp;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+
+use crate::{common, debug_server, launch, session, tunnels, JitStreamerState};
+
+const DEFAULT_ADDRESS: &str = "http://127.0.0.1:49151";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the RSD port has to accept a connection to count as healthy
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug)]
+pub enum TunneldError {
+    /// tunneld isn't running, or isn't where TUNNELD_ADDRESS says
+    Unreachable(String),
+    BadResponse(String),
+}
+
+impl std::fmt::Display for TunneldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TunneldError::Unreachable(e) => write!(f, "tunneld is not reachable: {e}"),
+            TunneldError::BadResponse(e) => write!(f, "bad response from tunneld: {e}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tunnel {
+    #[serde(rename(deserialize = "tunnel-address"))]
+    pub address: IpAddr,
+    /// The device's RSD port on the other end of the tunnel
+    #[serde(rename(deserialize = "tunnel-port"))]
+    pub port: u16,
+    #[serde(default)]
+    pub interface: Option<String>,
+}
+
+impl Tunnel {
+    /// Whether the device's RSD port answers through the tunnel
+    pub async fn is_healthy(&self) -> bool {
+        matches!(
+            tokio::time::timeout(
+                HEALTH_TIMEOUT,
+                TcpStream::connect((self.address, self.port))
+            )
+            .await,
+            Ok(Ok(_))
+        )
+    }
+}
+
+#[derive(Clone)]
+pub struct TunneldClient {
+    address: String,
+    client: reqwest::Client,
+}
+
+impl TunneldClient {
+    pub fn new(address: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Uses TUNNELD_ADDRESS, or tunneld's default port on localhost
+    pub fn from_env() -> Self {
+        Self::new(&std::env::var("TUNNELD_ADDRESS").unwrap_or(DEFAULT_ADDRESS.to_string()))
+    }
+
+    /// Every tunnel tunneld has, by UDID
+    pub async fn list(&self) -> Result<HashMap<String, Vec<Tunnel>>, TunneldError> {
+        let res = self
+            .client
+            .get(&self.address)
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .await
+            .map_err(|e| TunneldError::Unreachable(e.to_string()))?;
+        if !res.status().is_success() {
+            return Err(TunneldError::BadResponse(res.status().to_string()));
+        }
+        res.json()
+            .await
+            .map_err(|e| TunneldError::BadResponse(e.to_string()))
+    }
+
+    pub async fn tunnel(&self, udid: &str) -> Result<Option<Tunnel>, TunneldError> {
+        Ok(self
+            .list()
+            .await?
+            .remove(udid)
+            .and_then(|t| t.into_iter().next()))
+    }
+
+    /// Waits for the device's tunnel to come up.
+    /// Gives up straight away if tunneld itself can't be reached, there's no point waiting.
+    pub async fn wait_for_tunnel(
+        &self,
+        udid: &str,
+        attempts: u32,
+        delay: Duration,
+    ) -> Result<Option<Tunnel>, TunneldError> {
+        for attempt in 0..attempts {
+            if let Some(tunnel) = self.tunnel(udid).await? {
+                return Ok(Some(tunnel));
+            }
+            debug!("No tunnel for {udid} yet, attempt {attempt}");
+            tokio::time::sleep(delay).await;
+        }
+        Ok(None)
+    }
+}
+
+#[derive(Serialize)]
+pub struct TunnelReturn {
+    ok: bool,
+    tunnel: Option<Tunnel>,
+    healthy: bool,
+    error: Option<String>,
+    error_code: Option<&'static str>,
+}
+
+/// Checks that the server can reach the device through its tunnel.
+/// The tunnel is opened for the check unless the device is already busy.
+pub async fn check(
+    ip: SecureClientIp,
+    State(state): State<JitStreamerState>,
+) -> Json<TunnelReturn> {
+    let ip = ip.0.to_string();
+    let udid = match common::get_udid_from_ip(ip.clone()).await {
+        Ok(u) => u,
+        Err(e) => {
+            return Json(TunnelReturn {
+                ok: false,
+                tunnel: None,
+                healthy: false,
+                error: Some(e),
+                error_code: None,
+            })
+        }
+    };
+    info!("Checking the tunnel for {udid}");
+
+    // Registering and unregistering would pull the device out from under a launch or session
+    let busy = !debug_server::active_jobs(&udid).is_empty()
+        || session::is_running(&state.sessions, &udid).await;
+    let res = if busy && launch::tunnel_mode() == launch::TunnelMode::Builtin {
+        match tunnels::tunnel(&udid) {
+            Some(tunnel) => {
+                let healthy = tunnel.is_healthy().await;
+                Ok((tunnel, healthy))
+            }
+            None => Err(launch::LaunchError::TunnelNotFound),
+        }
+    } else if busy {
+        match TunneldClient::from_env().tunnel(&udid).await {
+            Ok(Some(tunnel)) => {
+                let healthy = tunnel.is_healthy().await;
+                Ok((tunnel, healthy))
+            }
+            Ok(None) => Err(launch::LaunchError::TunnelNotFound),
+            Err(e) => Err(launch::LaunchError::Tunneld(e)),
+        }
+    } else {
+        launch::check_tunnel(&udid, &ip).await
+    };
+
+    Json(match res {
+        Ok((tunnel, healthy)) => TunnelReturn {
+            ok: healthy,
+            tunnel: Some(tunnel),
+            healthy,
+            error: (!healthy).then(|| "The tunnel is up but the device isn't answering".into()),
+            error_code: (!healthy).then_some("tunnel_unhealthy"),
+        },
+        Err(e) => TunnelReturn {
+            ok: false,
+            tunnel: None,
+            healthy: false,
+            error: Some(e.to_string()),
+            error_code: Some(e.code()),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use axum::{http::StatusCode, routing::get};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const TUNNELS: &str = r#"{
+        "udid-1": [
+            {"tunnel-address": "fd7b:e5b:6f53::1", "tunnel-port": 58783, "interface": "utun3"},
+            {"tunnel-address": "fd7b:e5b:6f53::2", "tunnel-port": 58784, "interface": "utun4"}
+        ],
+        "udid-2": [{"tunnel-address": "10.7.0.2", "tunnel-port": 49152}]
+    }"#;
+
+    /// Serves the router on a loopback port, returning its address
+    async fn stub_tunneld(app: axum::Router) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        tokio::task::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        address
+    }
+
+    /// A loopback port with nothing listening on it
+    async fn closed_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn lists_tunnels() {
+        let address = stub_tunneld(axum::Router::new().route("/", get(|| async { TUNNELS }))).await;
+        let client = TunneldClient::new(&address);
+
+        let tunnels = client.list().await.unwrap();
+        assert_eq!(tunnels.len(), 2);
+        assert_eq!(tunnels["udid-1"].len(), 2);
+
+        let tunnel = client.tunnel("udid-1").await.unwrap().unwrap();
+        assert_eq!(
+            tunnel.address,
+            "fd7b:e5b:6f53::1".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(tunnel.port, 58783);
+        assert_eq!(tunnel.interface.as_deref(), Some("utun3"));
+
+        let tunnel = client.tunnel("udid-2").await.unwrap().unwrap();
+        assert_eq!(tunnel.address, "10.7.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(tunnel.interface, None);
+
+        assert_eq!(client.tunnel("udid-3").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn reports_bad_responses() {
+        let app = axum::Router::new()
+            .route("/", get(|| async { "not json" }))
+            .route(
+                "/broken",
+                get(|| async { (StatusCode::INTERNAL_SERVER_ERROR, "oops") }),
+            );
+        let address = stub_tunneld(app).await;
+
+        assert!(matches!(
+            TunneldClient::new(&address).list().await,
+            Err(TunneldError::BadResponse(_))
+        ));
+        assert!(matches!(
+            TunneldClient::new(&format!("{address}/broken"))
+                .list()
+                .await,
+            Err(TunneldError::BadResponse(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn fails_fast_without_tunneld() {
+        let client = TunneldClient::new(&format!("http://127.0.0.1:{}", closed_port().await));
+        let start = std::time::Instant::now();
+        assert!(matches!(
+            client
+                .wait_for_tunnel("udid-1", 15, Duration::from_secs(1))
+                .await,
+            Err(TunneldError::Unreachable(_))
+        ));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_tunnel() {
+        // No tunnels for the first two requests
+        let requests = Arc::new(AtomicU32::new(0));
+        let counter = requests.clone();
+        let app = axum::Router::new().route(
+            "/",
+            get(move || async move {
+                match counter.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => "{}",
+                    _ => TUNNELS,
+                }
+            }),
+        );
+        let client = TunneldClient::new(&stub_tunneld(app).await);
+
+        let tunnel = client
+            .wait_for_tunnel("udid-2", 5, Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert_eq!(tunnel.map(|t| t.port), Some(49152));
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        assert_eq!(
+            client
+                .wait_for_tunnel("udid-3", 2, Duration::from_millis(10))
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn checks_health() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = Tunnel {
+            address: "127.0.0.1".parse().unwrap(),
+            port: listener.local_addr().unwrap().port(),
+            interface: None,
+        };
+        assert!(open.is_healthy().await);
+
+        let closed = Tunnel {
+            port: closed_port().await,
+            ..open
+        };
+        assert!(!closed.is_healthy().await);
+    }
+}
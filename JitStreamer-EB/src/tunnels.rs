@@ -0,0 +1,450 @@
+// Jackson Coxson
+// Tunnels to each device's CoreDevice proxy, so the server doesn't need netmuxd and tunneld.
+// Each tunnel gets its own TUN interface with the address the device hands out.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv6Addr},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        LazyLock, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use idevice::{core_device_proxy::CoreDeviceProxy, provider::TcpProvider, IdeviceService};
+use log::{info, warn};
+use serde::Serialize;
+use tokio::{io::unix::AsyncFd, sync::watch, task::AbortHandle};
+
+use crate::{common, debug_server, tunneld::Tunnel};
+
+/// Unpinned tunnels are kept this long in case the device launches again
+const TUNNEL_IDLE: Duration = Duration::from_secs(5 * 60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+const INTERFACE_PREFIX: &str = "jse";
+
+static TUNNELS: LazyLock<Mutex<HashMap<String, Entry>>> = LazyLock::new(Default::default);
+static NEXT_INTERFACE: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum TunnelState {
+    Connecting,
+    Up {
+        #[serde(flatten)]
+        tunnel: Tunnel,
+        since: String,
+    },
+    Failed {
+        error: String,
+        since: String,
+    },
+}
+
+struct Entry {
+    state: watch::Sender<TunnelState>,
+    /// Launches and sessions using the tunnel, it isn't closed while any are
+    pins: u32,
+    idle_since: Instant,
+    task: AbortHandle,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TunnelInfo {
+    pub udid: String,
+    #[serde(flatten)]
+    pub state: TunnelState,
+    pub pins: u32,
+}
+
+/// Keeps the device's tunnel open until dropped, or until `release` if kept
+pub struct TunnelPin {
+    udid: Option<String>,
+}
+
+impl TunnelPin {
+    /// Leaves the tunnel pinned after this is dropped, for sessions that outlive the launch
+    pub fn keep(mut self) {
+        self.udid = None;
+    }
+}
+
+impl Drop for TunnelPin {
+    fn drop(&mut self) {
+        if let Some(udid) = &self.udid {
+            release(udid);
+        }
+    }
+}
+
+/// Opens a tunnel to the device, or reuses the one that's already open, and pins it
+pub async fn open(udid: &str, ip: &str) -> Result<(Tunnel, TunnelPin), String> {
+    let mut state = {
+        let mut tunnels = TUNNELS.lock().unwrap();
+        let reusable = tunnels
+            .get(udid)
+            .is_some_and(|e| !matches!(*e.state.borrow(), TunnelState::Failed { .. }));
+        if !reusable {
+            // Whoever pinned the failed tunnel still unpins it when they're done
+            let pins = tunnels.remove(udid).map_or(0, |old| {
+                old.task.abort();
+                old.pins
+            });
+            let (sender, _) = watch::channel(TunnelState::Connecting);
+            let task = tokio::task::spawn(run(udid.to_string(), ip.to_string(), sender.clone()));
+            tunnels.insert(
+                udid.to_string(),
+                Entry {
+                    state: sender,
+                    pins,
+                    idle_since: Instant::now(),
+                    task: task.abort_handle(),
+                },
+            );
+        }
+        let entry = tunnels.get_mut(udid).unwrap();
+        entry.pins += 1;
+        entry.state.subscribe()
+    };
+    // Unpins if this is cancelled while the tunnel comes up
+    let pin = TunnelPin {
+        udid: Some(udid.to_string()),
+    };
+
+    let res = state
+        .wait_for(|s| !matches!(s, TunnelState::Connecting))
+        .await
+        .map(|s| s.clone());
+    match res {
+        Ok(TunnelState::Up { tunnel, .. }) => Ok((tunnel, pin)),
+        Ok(TunnelState::Failed { error, .. }) => Err(error),
+        _ => Err("tunnel was closed".to_string()),
+    }
+}
+
+/// Unpins a tunnel kept with `TunnelPin::keep`
+pub fn release(udid: &str) {
+    if let Some(entry) = TUNNELS.lock().unwrap().get_mut(udid) {
+        entry.pins = entry.pins.saturating_sub(1);
+        if entry.pins == 0 {
+            entry.idle_since = Instant::now();
+        }
+    }
+}
+
+/// The device's tunnel, if it's up
+pub fn tunnel(udid: &str) -> Option<Tunnel> {
+    match &*TUNNELS.lock().unwrap().get(udid)?.state.borrow() {
+        TunnelState::Up { tunnel, .. } => Some(tunnel.clone()),
+        _ => None,
+    }
+}
+
+pub fn list() -> Vec<TunnelInfo> {
+    let mut tunnels = TUNNELS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(udid, e)| TunnelInfo {
+            udid: udid.clone(),
+            state: e.state.borrow().clone(),
+            pins: e.pins,
+        })
+        .collect::<Vec<_>>();
+    tunnels.sort_by(|a, b| a.udid.cmp(&b.udid));
+    tunnels
+}
+
+/// Closes tunnels nothing has used for a while, and forgets failed ones
+pub async fn maintain() {
+    loop {
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        TUNNELS.lock().unwrap().retain(|udid, e| {
+            let failed = matches!(*e.state.borrow(), TunnelState::Failed { .. });
+            if e.pins == 0 && (failed || e.idle_since.elapsed() > TUNNEL_IDLE) {
+                info!("Closing tunnel to {udid}");
+                e.task.abort();
+                return false;
+            }
+            true
+        });
+    }
+}
+
+/// Connects to the device and carries its packets until the connection drops
+async fn run(udid: String, ip: String, state: watch::Sender<TunnelState>) {
+    let (proxy, tun) = match tokio::time::timeout(CONNECT_TIMEOUT, connect(&udid, &ip)).await {
+        Ok(Ok(t)) => t,
+        Ok(Err(e)) => return failed(&udid, &state, e),
+        Err(_) => return failed(&udid, &state, "timed out connecting".to_string()),
+    };
+
+    let address = match proxy.handshake.server_address.parse::<IpAddr>() {
+        Ok(a) => a,
+        Err(e) => return failed(&udid, &state, format!("bad server address: {e}")),
+    };
+    let tunnel = Tunnel {
+        address,
+        port: proxy.handshake.server_rsd_port,
+        interface: Some(tun.name.clone()),
+    };
+    info!(
+        "Tunnel to {udid} is up on {} at [{address}]:{}",
+        tun.name, tunnel.port
+    );
+    state.send_replace(TunnelState::Up {
+        tunnel,
+        since: debug_server::timestamp(),
+    });
+
+    let error = pump(proxy, tun).await;
+    failed(&udid, &state, error);
+}
+
+fn failed(udid: &str, state: &watch::Sender<TunnelState>, error: String) {
+    warn!("Tunnel to {udid} failed: {error}");
+    state.send_replace(TunnelState::Failed {
+        error,
+        since: debug_server::timestamp(),
+    });
+}
+
+async fn connect(udid: &str, ip: &str) -> Result<(CoreDeviceProxy, TunDevice), String> {
+    let pairing_file = common::get_pairing_file(udid)
+        .await
+        .map_err(|e| format!("failed to get pairing file: {e}"))?;
+    let provider = TcpProvider {
+        addr: ip.parse().map_err(|e| format!("bad device address: {e}"))?,
+        pairing_file,
+        label: "JitStreamer-EB".to_string(),
+    };
+    let proxy = CoreDeviceProxy::connect(&provider)
+        .await
+        .map_err(|e| format!("failed to start the CoreDevice proxy: {e}"))?;
+
+    let params = &proxy.handshake.client_parameters;
+    let prefix = params
+        .netmask
+        .parse::<Ipv6Addr>()
+        .map(|m| u128::from(m).count_ones())
+        .unwrap_or(64);
+    let name = format!(
+        "{INTERFACE_PREFIX}{}",
+        NEXT_INTERFACE.fetch_add(1, Ordering::Relaxed)
+    );
+    let tun = TunDevice::create(&name).map_err(|e| format!("failed to create {name}: {e}"))?;
+    tun.configure(&params.address, prefix, params.mtu)
+        .await
+        .map_err(|e| format!("failed to configure {name}: {e}"))?;
+    Ok((proxy, tun))
+}
+
+/// Moves packets between the interface and the device, returning why it stopped
+async fn pump(mut proxy: CoreDeviceProxy, tun: TunDevice) -> String {
+    let mut from_tun = vec![0; u16::MAX as usize];
+    let mut from_device = Vec::new();
+    loop {
+        tokio::select! {
+            res = proxy.recv() => {
+                let data = match res {
+                    Ok(d) if d.is_empty() => return "device closed the tunnel".to_string(),
+                    Ok(d) => d,
+                    Err(e) => return format!("device error: {e}"),
+                };
+                // The proxy hands back a byte stream, the interface wants whole packets
+                from_device.extend_from_slice(&data);
+                while let Some(packet) = next_packet(&mut from_device) {
+                    if let Err(e) = tun.write(&packet).await {
+                        return format!("interface error: {e}");
+                    }
+                }
+            }
+            res = tun.read(&mut from_tun) => {
+                let len = match res {
+                    Ok(l) => l,
+                    Err(e) => return format!("interface error: {e}"),
+                };
+                if let Err(e) = proxy.send(&from_tun[..len]).await {
+                    return format!("device error: {e}");
+                }
+            }
+        }
+    }
+}
+
+/// Takes the next whole IP packet off the buffer, dropping anything that can't be framed
+fn next_packet(buf: &mut Vec<u8>) -> Option<Vec<u8>> {
+    loop {
+        match frame_at(buf)? {
+            Frame::Packet(len) => return Some(buf.drain(..len).collect()),
+            Frame::Discard(len) => {
+                warn!("Dropping {len} bytes from the device that aren't an IP packet");
+                buf.drain(..len);
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Frame {
+    /// A whole packet of this many bytes
+    Packet(usize),
+    /// This many bytes that aren't a packet
+    Discard(usize),
+}
+
+/// What's at the start of the buffer, if all of it is there
+fn frame_at(buf: &[u8]) -> Option<Frame> {
+    let len = match buf.first()? >> 4 {
+        6 => 40 + u16::from_be_bytes([*buf.get(4)?, *buf.get(5)?]) as usize,
+        4 => match u16::from_be_bytes([*buf.get(2)?, *buf.get(3)?]) as usize {
+            // Shorter than its own header, there's no telling where the next packet starts
+            len if len < 20 => return Some(Frame::Discard(buf.len())),
+            len => len,
+        },
+        // Not something we can frame, drop it all
+        _ => return Some(Frame::Discard(buf.len())),
+    };
+    (buf.len() >= len).then_some(Frame::Packet(len))
+}
+
+/// A TUN interface, removed by the kernel when this is dropped
+struct TunDevice {
+    fd: AsyncFd<OwnedFd>,
+    name: String,
+}
+
+impl TunDevice {
+    fn create(name: &str) -> io::Result<Self> {
+        let fd = unsafe {
+            libc::open(
+                c"/dev/net/tun".as_ptr(),
+                libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut req: libc::ifreq = unsafe { std::mem::zeroed() };
+        for (d, s) in req
+            .ifr_name
+            .iter_mut()
+            .zip(name.bytes().take(libc::IFNAMSIZ - 1))
+        {
+            *d = s as libc::c_char;
+        }
+        req.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+        if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNSETIFF, &req) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+            name: name.to_string(),
+        })
+    }
+
+    async fn configure(&self, address: &str, prefix: u32, mtu: u16) -> io::Result<()> {
+        let address = format!("{address}/{prefix}");
+        let mtu = mtu.to_string();
+        for args in [
+            vec!["-6", "addr", "add", &address, "dev", &self.name],
+            vec!["link", "set", "dev", &self.name, "mtu", &mtu, "up"],
+        ] {
+            let output = tokio::process::Command::new("ip")
+                .args(&args)
+                .output()
+                .await?;
+            if !output.status.success() {
+                return Err(io::Error::other(
+                    String::from_utf8_lossy(&output.stderr).trim().to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            if let Ok(res) = guard.try_io(|fd| {
+                let n = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+                if n < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(n as usize)
+                }
+            }) {
+                return res;
+            }
+        }
+    }
+
+    async fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.writable().await?;
+            if let Ok(res) = guard.try_io(|fd| {
+                let n = unsafe { libc::write(fd.as_raw_fd(), buf.as_ptr().cast(), buf.len()) };
+                if n < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(n as usize)
+                }
+            }) {
+                return res;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_packets() {
+        // IPv6 header with 4 bytes of payload
+        let mut v6 = vec![0x60, 0, 0, 0, 0, 4];
+        v6.resize(44, 0);
+        assert_eq!(frame_at(&v6), Some(Frame::Packet(44)));
+        assert_eq!(frame_at(&v6[..43]), None);
+        assert_eq!(frame_at(&v6[..3]), None);
+
+        // IPv4 total length 20
+        let mut v4 = vec![0x45, 0, 0, 20];
+        v4.resize(20, 0);
+        let mut both = v4.clone();
+        both.extend_from_slice(&v6);
+        assert_eq!(frame_at(&both), Some(Frame::Packet(20)));
+        assert_eq!(frame_at(&both[20..]), Some(Frame::Packet(44)));
+
+        assert_eq!(frame_at(&both[..2]), None);
+        assert_eq!(frame_at(&both[..19]), None);
+
+        // Garbage is dropped whole, including IPv4 lengths that can't hold a header
+        assert_eq!(frame_at(&[]), None);
+        assert_eq!(frame_at(&[0x10, 1, 2]), Some(Frame::Discard(3)));
+        assert_eq!(frame_at(&[0x45, 0, 0, 0, 1, 2]), Some(Frame::Discard(6)));
+        assert_eq!(frame_at(&[0x45, 0, 0, 19, 1]), Some(Frame::Discard(5)));
+
+        // Only whole packets come out, and garbage never does
+        let mut buf = both[..30].to_vec();
+        assert_eq!(next_packet(&mut buf), Some(v4.clone()));
+        assert_eq!(next_packet(&mut buf), None);
+        buf.extend_from_slice(&both[30..]);
+        assert_eq!(next_packet(&mut buf), Some(v6.clone()));
+        assert!(buf.is_empty());
+
+        let mut buf = vec![0x10, 1, 2];
+        assert_eq!(next_packet(&mut buf), None);
+        assert!(buf.is_empty());
+        buf.extend_from_slice(&v4);
+        assert_eq!(next_packet(&mut buf), Some(v4));
+        assert_eq!(next_packet(&mut buf), None);
+    }
+}
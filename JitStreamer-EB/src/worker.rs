@@ -0,0 +1,538 @@
+// Jackson Coxson
+// Lets workers on other hosts pull jobs from this server's launch queue.
+// A worker claims a job, heartbeats it to keep the lease, then reports it complete or failed.
+// Every request carries WORKER_SECRET as a bearer token.
+
+use std::time::Duration;
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    common,
+    debug_server::{self, Claim, JobKind, JobStatus, LaunchJob, Progress},
+    launch::{self, LaunchError},
+    runner::Cancellations,
+};
+
+pub const SECRET_VAR: &str = "WORKER_SECRET";
+/// WORKER_SECRET as it was at startup, the /worker endpoints are disabled without it
+#[derive(Debug, Clone, Default)]
+pub struct WorkerSecret(Option<String>);
+
+impl WorkerSecret {
+    pub fn from_env() -> Self {
+        Self(std::env::var(SECRET_VAR).ok())
+    }
+
+    pub fn check(&self, headers: &HeaderMap) -> Result<(), (StatusCode, &'static str)> {
+        common::check_token(headers, self.0.as_deref())
+    }
+}
+
+/// How long a claim waits for a job before returning empty
+const CLAIM_WAIT: Duration = Duration::from_secs(20);
+/// How long a worker waits after failing to reach the server
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize)]
+pub struct ClaimRequest {
+    worker: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteJob {
+    pub job_id: i64,
+    pub attempt: i64,
+    pub udid: String,
+    pub ip: String,
+    pub kind: JobKind,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ClaimReturn {
+    ok: bool,
+    job: Option<RemoteJob>,
+    lease_seconds: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HeartbeatRequest {
+    job_id: i64,
+    attempt: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HeartbeatReturn {
+    ok: bool,
+    /// The claim is gone, the worker should stop
+    cancelled: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ProgressRequest {
+    job_id: i64,
+    attempt: i64,
+    status: JobStatus,
+    pid: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CompleteRequest {
+    job_id: i64,
+    attempt: i64,
+    pid: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FailRequest {
+    job_id: i64,
+    attempt: i64,
+    error: String,
+    error_code: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WorkerReturn {
+    ok: bool,
+}
+
+/// Remote workers don't keep sessions, so persistent launches stay on this server
+fn remote_can_run(kind: &JobKind) -> bool {
+    !matches!(kind, JobKind::Launch { options, .. } if options.persistent)
+}
+
+/// Waits up to CLAIM_WAIT for a job and hands it to the worker
+pub async fn claim(
+    headers: HeaderMap,
+    State(secret): State<WorkerSecret>,
+    State(cancellations): State<Cancellations>,
+    Json(req): Json<ClaimRequest>,
+) -> Result<Json<ClaimReturn>, (StatusCode, &'static str)> {
+    secret.check(&headers)?;
+    let job = debug_server::next_job_where(remote_can_run, CLAIM_WAIT).await;
+    if let Some(job) = &job {
+        info!(
+            "Remote worker {} claimed job to {} for UDID: {}, Ordinal: {}, attempt {}",
+            req.worker, job.kind, job.udid, job.ordinal, job.attempt
+        );
+        watch_cancel(cancellations, job).await;
+    }
+    Ok(Json(ClaimReturn {
+        ok: true,
+        job: job.map(|j| RemoteJob {
+            job_id: j.ordinal,
+            attempt: j.attempt,
+            udid: j.udid,
+            ip: j.ip,
+            kind: j.kind,
+        }),
+        lease_seconds: debug_server::LEASE_DURATION.as_secs(),
+    }))
+}
+
+/// Lets /status cancel a remotely claimed job. The worker finds out on its next heartbeat.
+async fn watch_cancel(cancellations: Cancellations, job: &LaunchJob) {
+    let (sender, mut cancel) = oneshot::channel::<oneshot::Sender<bool>>();
+    cancellations.lock().await.insert(job.ordinal, sender);
+    let (ordinal, attempt) = (job.ordinal, job.attempt);
+    let mut info = match debug_server::watch_job(ordinal) {
+        Some(i) => i,
+        None => return,
+    };
+    tokio::task::spawn(async move {
+        let reply = tokio::select! {
+            Ok(reply) = &mut cancel => Some(reply),
+            _ = info.wait_for(|i| i.finished_at.is_some() || i.attempts != attempt) => None,
+        };
+        if let Some(reply) = reply {
+            let before_launch = matches!(
+                debug_server::get_job(ordinal),
+                Some(j) if j.status == JobStatus::Claimed
+            );
+            debug_server::cancel_claimed(ordinal, attempt);
+            reply.send(before_launch).ok();
+            return;
+        }
+        // Only clean up our own entry, another worker may have claimed the job since
+        drop(cancel);
+        let mut cancellations = cancellations.lock().await;
+        if cancellations.get(&ordinal).is_some_and(|s| s.is_closed()) {
+            cancellations.remove(&ordinal);
+        }
+    });
+}
+
+pub async fn heartbeat(
+    headers: HeaderMap,
+    State(secret): State<WorkerSecret>,
+    Json(req): Json<HeartbeatRequest>,
+) -> Result<Json<HeartbeatReturn>, (StatusCode, &'static str)> {
+    secret.check(&headers)?;
+    let held = debug_server::renew_lease(req.job_id, req.attempt);
+    Ok(Json(HeartbeatReturn {
+        ok: true,
+        cancelled: !held,
+    }))
+}
+
+/// Records how far the worker got, so a cancel knows whether the app was launched
+pub async fn progress(
+    headers: HeaderMap,
+    State(secret): State<WorkerSecret>,
+    Json(req): Json<ProgressRequest>,
+) -> Result<Json<WorkerReturn>, (StatusCode, &'static str)> {
+    secret.check(&headers)?;
+    // Finishing goes through /complete and /fail
+    if !matches!(req.status, JobStatus::Launching | JobStatus::Attached) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "only launching and attached can be reported",
+        ));
+    }
+    let claim = Claim {
+        ordinal: req.job_id,
+        attempt: req.attempt,
+    };
+    debug_server::set_status(claim, req.status, req.pid);
+    Ok(Json(WorkerReturn { ok: true }))
+}
+
+pub async fn complete(
+    headers: HeaderMap,
+    State(secret): State<WorkerSecret>,
+    Json(req): Json<CompleteRequest>,
+) -> Result<Json<WorkerReturn>, (StatusCode, &'static str)> {
+    secret.check(&headers)?;
+    info!(
+        "Remote worker enabled JIT for PID {} ({})",
+        req.pid, req.job_id
+    );
+    debug_server::complete(req.job_id, req.attempt, JobStatus::Detached, req.pid);
+    Ok(Json(WorkerReturn { ok: true }))
+}
+
+pub async fn fail(
+    headers: HeaderMap,
+    State(secret): State<WorkerSecret>,
+    Json(req): Json<FailRequest>,
+) -> Result<Json<WorkerReturn>, (StatusCode, &'static str)> {
+    secret.check(&headers)?;
+    warn!("Remote worker failed job {}: {}", req.job_id, req.error);
+    debug_server::fail(req.job_id, req.attempt, req.error, &req.error_code);
+    Ok(Json(WorkerReturn { ok: true }))
+}
+
+/// Talks to the server's /worker endpoints
+pub struct WorkerClient {
+    url: String,
+    secret: String,
+    client: reqwest::Client,
+}
+
+impl WorkerClient {
+    pub fn new(url: &str, secret: String) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            secret,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    async fn post<Req: Serialize, Res: for<'de> Deserialize<'de>>(
+        &self,
+        path: &str,
+        body: &Req,
+        timeout: Duration,
+    ) -> Result<Res, reqwest::Error> {
+        self.client
+            .post(format!("{}/worker/{path}", self.url))
+            .bearer_auth(&self.secret)
+            .timeout(timeout)
+            .json(body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+
+    /// Waits for a job, returning None if there wasn't one in time
+    pub async fn claim(&self, worker: &str) -> Result<Option<RemoteJob>, reqwest::Error> {
+        let res: ClaimReturn = self
+            .post(
+                "claim",
+                &ClaimRequest {
+                    worker: worker.to_string(),
+                },
+                CLAIM_WAIT + Duration::from_secs(10),
+            )
+            .await?;
+        Ok(res.job)
+    }
+
+    /// Renews the lease, returning false if the job was cancelled or given away
+    pub async fn heartbeat(&self, job: &RemoteJob) -> Result<bool, reqwest::Error> {
+        let res: HeartbeatReturn = self
+            .post(
+                "heartbeat",
+                &HeartbeatRequest {
+                    job_id: job.job_id,
+                    attempt: job.attempt,
+                },
+                Duration::from_secs(10),
+            )
+            .await?;
+        Ok(!res.cancelled)
+    }
+
+    pub async fn progress(
+        &self,
+        job: &RemoteJob,
+        status: JobStatus,
+        pid: Option<u64>,
+    ) -> Result<(), reqwest::Error> {
+        let _: WorkerReturn = self
+            .post(
+                "progress",
+                &ProgressRequest {
+                    job_id: job.job_id,
+                    attempt: job.attempt,
+                    status,
+                    pid,
+                },
+                Duration::from_secs(10),
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn complete(&self, job: &RemoteJob, pid: u64) -> Result<(), reqwest::Error> {
+        let _: WorkerReturn = self
+            .post(
+                "complete",
+                &CompleteRequest {
+                    job_id: job.job_id,
+                    attempt: job.attempt,
+                    pid,
+                },
+                Duration::from_secs(10),
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn fail(&self, job: &RemoteJob, error: &LaunchError) -> Result<(), reqwest::Error> {
+        let _: WorkerReturn = self
+            .post(
+                "fail",
+                &FailRequest {
+                    job_id: job.job_id,
+                    attempt: job.attempt,
+                    error: error.to_string(),
+                    error_code: error.code().to_string(),
+                },
+                Duration::from_secs(10),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+/// Runs as a worker for the server at `url` instead of serving clients.
+/// The device's pairing file needs to be on this host, and netmuxd and tunneld with TUNNEL_MODE=tunneld.
+pub async fn run_remote(url: String, secret: String, count: u32) {
+    let name = std::env::var("WORKER_NAME").unwrap_or("remote".to_string());
+    info!("Starting {count} remote workers for {url}");
+    let mut handles = Vec::new();
+    for worker in 0..count {
+        let client = WorkerClient::new(&url, secret.clone());
+        let name = format!("{name}-{worker}");
+        handles.push(tokio::task::spawn(async move {
+            loop {
+                let job = match client.claim(&name).await {
+                    Ok(Some(j)) => j,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        warn!("Worker {name} failed to claim a job: {e}");
+                        tokio::time::sleep(RETRY_DELAY).await;
+                        continue;
+                    }
+                };
+                info!(
+                    "Worker {name} claimed job to {} for UDID: {}, Ordinal: {}, attempt {}",
+                    job.kind, job.udid, job.job_id, job.attempt
+                );
+
+                let (progress, mut updates) = mpsc::unbounded_channel();
+                let work = run_job(&job, Progress::Remote(progress));
+                tokio::pin!(work);
+                let res = loop {
+                    tokio::select! {
+                        res = &mut work => break Some(res),
+                        Some((status, pid)) = updates.recv() => {
+                            if let Err(e) = client.progress(&job, status, pid).await {
+                                warn!("Worker {name} failed to report progress: {e}");
+                            }
+                        }
+                        _ = tokio::time::sleep(debug_server::LEASE_DURATION / 3) => {
+                            match client.heartbeat(&job).await {
+                                Ok(true) => {}
+                                Ok(false) => break None,
+                                Err(e) => warn!("Worker {name} failed to heartbeat: {e}"),
+                            }
+                        }
+                    }
+                };
+
+                let reported = match res {
+                    None => {
+                        info!("Worker {name} dropped job {}, it was cancelled", job.job_id);
+                        Ok(())
+                    }
+                    Some(Ok(pid)) => {
+                        info!("Worker {name} enabled JIT for PID {pid} ({})", job.kind);
+                        client.complete(&job, pid).await
+                    }
+                    Some(Err(e)) => {
+                        warn!("Worker {name} failed to {}: {e}", job.kind);
+                        client.fail(&job, &e).await
+                    }
+                };
+                // The lease will run out and the job will be tried again
+                if let Err(e) = reported {
+                    warn!("Worker {name} failed to report job {}: {e}", job.job_id);
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.await.ok();
+    }
+}
+
+async fn run_job(job: &RemoteJob, progress: Progress) -> Result<u64, LaunchError> {
+    match &job.kind {
+        JobKind::Launch { bundle_id, options } => {
+            launch::launch_app(&job.udid, &job.ip, bundle_id, options, &progress).await
+        }
+        JobKind::Attach(target) => launch::attach(&job.udid, &job.ip, target, &progress).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::{extract::FromRef, routing::post};
+
+    use crate::{debug_server::LaunchOptions, runner};
+
+    const SECRET: &str = "loopback-secret";
+
+    fn launch(bundle_id: &str, persistent: bool) -> JobKind {
+        JobKind::Launch {
+            bundle_id: bundle_id.to_string(),
+            options: LaunchOptions {
+                persistent,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[derive(Clone, FromRef)]
+    struct TestState {
+        cancellations: Cancellations,
+        secret: WorkerSecret,
+    }
+
+    async fn serve(cancellations: Cancellations) -> String {
+        let app = axum::Router::new()
+            .route("/worker/claim", post(claim))
+            .route("/worker/heartbeat", post(heartbeat))
+            .route("/worker/progress", post(progress))
+            .route("/worker/complete", post(complete))
+            .route("/worker/fail", post(fail))
+            .with_state(TestState {
+                cancellations,
+                secret: WorkerSecret(Some(SECRET.to_string())),
+            });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::task::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        url
+    }
+
+    #[tokio::test]
+    async fn loopback_worker() {
+        let _queue = debug_server::GLOBAL_QUEUE_TEST.lock().await;
+        let cancellations = Cancellations::default();
+        let url = serve(cancellations.clone()).await;
+
+        // The wrong secret can't claim anything
+        let intruder = WorkerClient::new(&url, "nope".to_string());
+        let err = intruder.claim("intruder").await.unwrap_err();
+        assert_eq!(err.status(), Some(reqwest::StatusCode::UNAUTHORIZED));
+
+        let client = WorkerClient::new(&url, SECRET.to_string());
+
+        // Persistent launches are skipped, the next job is handed out
+        let (session, _) = debug_server::add_to_queue("udid-1", "::1".into(), &launch("a", true));
+        let (ordinal, _) = debug_server::add_to_queue("udid-2", "::2".into(), &launch("b", false));
+        let job = client.claim("test").await.unwrap().unwrap();
+        assert_eq!(job.job_id, ordinal);
+        assert_eq!(job.udid, "udid-2");
+        assert_eq!(job.attempt, 1);
+        assert!(client.heartbeat(&job).await.unwrap());
+        client.complete(&job, 1234).await.unwrap();
+        let info = debug_server::get_job(ordinal).unwrap();
+        assert_eq!(info.status, JobStatus::Detached);
+        assert_eq!(info.pid, Some(1234));
+
+        // Failures keep their error code
+        let (ordinal, _) = debug_server::add_to_queue("udid-3", "::3".into(), &launch("c", false));
+        let job = client.claim("test").await.unwrap().unwrap();
+        client
+            .fail(&job, &LaunchError::AttachFailed("nope".to_string()))
+            .await
+            .unwrap();
+        let info = debug_server::get_job(ordinal).unwrap();
+        assert_eq!(info.status, JobStatus::Failed);
+        assert_eq!(info.error_code.as_deref(), Some("attach_failed"));
+
+        // Cancelling a claimed job tells the worker on its next heartbeat
+        let (ordinal, _) = debug_server::add_to_queue("udid-4", "::4".into(), &launch("d", false));
+        let job = client.claim("test").await.unwrap().unwrap();
+        assert_eq!(runner::cancel(&cancellations, ordinal).await, Some(true));
+        assert!(!client.heartbeat(&job).await.unwrap());
+        let info = debug_server::get_job(ordinal).unwrap();
+        assert_eq!(info.status, JobStatus::Cancelled);
+
+        // Once the worker says the app is launching, cancelling says so too
+        let (ordinal, _) = debug_server::add_to_queue("udid-5", "::5".into(), &launch("e", false));
+        let job = client.claim("test").await.unwrap().unwrap();
+        client
+            .progress(&job, JobStatus::Launching, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            debug_server::get_job(ordinal).unwrap().status,
+            JobStatus::Launching
+        );
+        assert_eq!(runner::cancel(&cancellations, ordinal).await, Some(false));
+        assert!(!client.heartbeat(&job).await.unwrap());
+        assert_eq!(
+            debug_server::get_job(ordinal).unwrap().status,
+            JobStatus::Cancelled
+        );
+
+        // Nobody remote took the persistent launch
+        assert!(debug_server::cancel_queued(session));
+    }
+}